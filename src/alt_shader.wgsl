@@ -1,8 +1,14 @@
-struct VertexInput {
+struct QuadInput {
     @location(0) position: vec2<f32>,
-    @location(1) tex_coords: vec2<f32>,
-    @location(2) bg: vec4<f32>,
-    @location(3) fg: vec4<f32>,
+};
+
+struct CellInput {
+    @location(1) cell: vec2<u32>,
+    @location(2) glyph_rect: vec4<i32>,
+    @location(3) atlas_rect: vec4<u32>,
+    @location(4) fg: vec4<f32>,
+    @location(5) bg: vec4<f32>,
+    @location(6) flags: u32,
 };
 
 struct VertexOutput {
@@ -12,15 +18,86 @@ struct VertexOutput {
     @location(2) fg: vec4<f32>,
 };
 
+struct ScreenUniform {
+    screen_size: vec2<f32>,
+    cell_size: vec2<f32>,
+    atlas_size: vec2<f32>,
+    _padding: vec2<f32>,
+};
+
+@group(1) @binding(0)
+var<uniform> screen: ScreenUniform;
+
 @vertex
 fn vs_main(
-    input: VertexInput,
+    quad: QuadInput,
+    cell: CellInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.clip_position = vec4(input.position, 0.0, 1.0);
-    out.tex_coords = input.tex_coords;
-    out.bg = input.bg;
-    out.fg = input.fg;
+
+    // cells without a glyph have an empty rect and are discarded as degenerate triangles
+    let glyph_rect = vec4<f32>(cell.glyph_rect);
+    let origin = vec2<f32>(cell.cell) * screen.cell_size;
+    let pixel = origin + glyph_rect.xy + quad.position * glyph_rect.zw;
+    let ndc = pixel / screen.screen_size * 2.0 - 1.0;
+    out.clip_position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+
+    let atlas_rect = vec4<f32>(cell.atlas_rect);
+    out.tex_coords = (atlas_rect.xy + quad.position * atlas_rect.zw) / screen.atlas_size;
+    out.bg = cell.bg;
+    out.fg = cell.fg;
+    return out;
+}
+
+struct BackgroundInput {
+    @location(1) cell: vec2<u32>,
+    @location(2) width: u32,
+    @location(3) color: vec4<f32>,
+};
+
+struct BackgroundOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+@vertex
+fn vs_background(
+    quad: QuadInput,
+    run: BackgroundInput,
+) -> BackgroundOutput {
+    var out: BackgroundOutput;
+
+    let origin = vec2<f32>(run.cell) * screen.cell_size;
+    let size = vec2<f32>(f32(run.width), 1.0) * screen.cell_size;
+    let pixel = origin + quad.position * size;
+    let ndc = pixel / screen.screen_size * 2.0 - 1.0;
+    out.clip_position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+    out.color = run.color;
+    return out;
+}
+
+@fragment
+fn fs_background(in: BackgroundOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
+
+struct RectInput {
+    @location(1) rect: vec4<i32>,
+    @location(2) color: vec4<f32>,
+};
+
+@vertex
+fn vs_rect(
+    quad: QuadInput,
+    rect: RectInput,
+) -> BackgroundOutput {
+    var out: BackgroundOutput;
+
+    let bounds = vec4<f32>(rect.rect);
+    let pixel = bounds.xy + quad.position * bounds.zw;
+    let ndc = pixel / screen.screen_size * 2.0 - 1.0;
+    out.clip_position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+    out.color = rect.color;
     return out;
 }
 
@@ -32,6 +109,7 @@ var samplerr: sampler;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let texture_color = textureSample(tex, samplerr, in.tex_coords);
-    return vec4(texture_color.x, texture_color.x, texture_color.x, 1.0);
+    // the atlas only stores glyph coverage in the red channel
+    let coverage = textureSample(tex, samplerr, in.tex_coords).r;
+    return vec4(in.fg.rgb, in.fg.a * coverage);
 }
@@ -0,0 +1,323 @@
+use crate::renderer::RenderFrame;
+use crate::text::{BackgroundInstance, CellInstance, RectInstance, ATLAS_SIZE};
+use image::{GrayImage, Rgba, RgbaImage};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use winit::dpi::PhysicalSize;
+use winit::window::Window;
+
+/// Which [`RenderBackend`] the app draws with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// wgpu pipeline, see [`crate::DisplayState`]
+    #[default]
+    Gpu,
+    /// CPU compositor, see [`SoftwareState`]
+    Cpu,
+}
+
+/// Something that can draw the output of [`crate::renderer::Renderer::prepare_render`]
+pub trait RenderBackend {
+    /// Replace the frame that will be drawn by the next call to [`RenderBackend::render`]
+    fn rerender_state(&mut self, frame: RenderFrame);
+
+    /// Draw the current frame to the window, does nothing when running headless
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError>;
+
+    /// Draw the current frame and read it back
+    fn render_to_image(&self) -> RgbaImage;
+
+    fn resize(&mut self, new_size: PhysicalSize<u32>);
+
+    fn size(&self) -> PhysicalSize<u32>;
+
+    /// `None` when running headless
+    fn window(&self) -> Option<&Arc<Window>>;
+}
+
+/// Pure CPU renderer, composites the glyph atlas into an [`RgbaImage`].
+/// Deterministic, so it can be used as the reference output for tests.
+pub struct SoftwareState {
+    window: Option<Arc<Window>>,
+    _context: Option<softbuffer::Context<Arc<Window>>>,
+    surface: Option<softbuffer::Surface<Arc<Window>, Arc<Window>>>,
+    size: PhysicalSize<u32>,
+
+    /// CPU copy of the glyph atlas
+    atlas: GrayImage,
+    instances: Vec<CellInstance>,
+    backgrounds: Vec<BackgroundInstance>,
+    overlays: Vec<RectInstance>,
+    background: Rgba<u8>,
+    cell_size: [u32; 2],
+}
+
+impl SoftwareState {
+    pub fn new(window: Arc<Window>) -> Self {
+        let size = window.inner_size();
+        let context = softbuffer::Context::new(Arc::clone(&window)).unwrap();
+        let surface = softbuffer::Surface::new(&context, Arc::clone(&window)).unwrap();
+
+        let mut state = Self::new_headless(size);
+        state.window = Some(window);
+        state._context = Some(context);
+        state.surface = Some(surface);
+        state.resize(size);
+        state
+    }
+
+    pub fn new_headless(size: PhysicalSize<u32>) -> Self {
+        Self {
+            window: None,
+            _context: None,
+            surface: None,
+            size,
+            atlas: GrayImage::new(ATLAS_SIZE, ATLAS_SIZE),
+            instances: Vec::new(),
+            backgrounds: Vec::new(),
+            overlays: Vec::new(),
+            background: Rgba([255, 255, 255, 255]),
+            cell_size: [1, 1],
+        }
+    }
+
+    /// Paint the backgrounds on a cleared frame, rasterize every glyph on top
+    /// and blend the overlays over them
+    fn draw(&self) -> RgbaImage {
+        let (width, height) = (self.size.width as i64, self.size.height as i64);
+        let mut image = RgbaImage::from_pixel(self.size.width, self.size.height, self.background);
+        let [cell_width, cell_height] = self.cell_size;
+
+        for run in &self.backgrounds {
+            let min_x = run.cell[0] as u32 * cell_width;
+            let min_y = run.cell[1] as u32 * cell_height;
+            let max_x = (min_x + run.width * cell_width).min(self.size.width);
+            let max_y = (min_y + cell_height).min(self.size.height);
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    image.put_pixel(x, y, Rgba(run.color));
+                }
+            }
+        }
+
+        for instance in self
+            .instances
+            .iter()
+            .filter(|instance| instance.flags & CellInstance::GLYPH != 0)
+        {
+            let [glyph_x, glyph_y, glyph_width, glyph_height] = instance.glyph_rect;
+            let [atlas_x, atlas_y, ..] = instance.atlas_rect;
+            let origin_x = instance.cell[0] as i64 * cell_width as i64 + glyph_x as i64;
+            let origin_y = instance.cell[1] as i64 * cell_height as i64 + glyph_y as i64;
+            let fg = Rgba(instance.fg);
+            let alpha = fg[3] as f32 / 255.0;
+
+            // Glyphs are stored in the atlas at the size they are drawn
+            for y in 0..glyph_height as i64 {
+                for x in 0..glyph_width as i64 {
+                    let (pixel_x, pixel_y) = (origin_x + x, origin_y + y);
+                    if !(0..width).contains(&pixel_x) || !(0..height).contains(&pixel_y) {
+                        continue;
+                    }
+
+                    let coverage = self
+                        .atlas
+                        .get_pixel(atlas_x as u32 + x as u32, atlas_y as u32 + y as u32)[0]
+                        as f32
+                        / 255.0;
+                    let pixel = image.get_pixel_mut(pixel_x as u32, pixel_y as u32);
+                    *pixel = blend_colors(*pixel, fg, coverage * alpha);
+                }
+            }
+        }
+
+        for overlay in &self.overlays {
+            let [x, y, rect_width, rect_height] = overlay.rect.map(|v| v as i64);
+            let color = Rgba(overlay.color);
+            let alpha = color[3] as f32 / 255.0;
+            for pixel_y in y.max(0)..(y + rect_height).min(height) {
+                for pixel_x in x.max(0)..(x + rect_width).min(width) {
+                    let pixel = image.get_pixel_mut(pixel_x as u32, pixel_y as u32);
+                    *pixel = blend_colors(*pixel, color, alpha);
+                }
+            }
+        }
+
+        image
+    }
+}
+
+impl RenderBackend for SoftwareState {
+    fn rerender_state(&mut self, frame: RenderFrame) {
+        for update in &frame.atlas_updates {
+            let width = update.rect.width() as usize;
+            for (row, data) in update.data.chunks_exact(width).enumerate() {
+                for (col, coverage) in data.iter().enumerate() {
+                    self.atlas.put_pixel(
+                        update.rect.min.x + col as u32,
+                        update.rect.min.y + row as u32,
+                        image::Luma([*coverage]),
+                    );
+                }
+            }
+        }
+        self.instances.resize(frame.cells, CellInstance::default());
+        for damage in frame.damage {
+            let range = damage.offset..damage.offset + damage.instances.len();
+            self.instances[range].copy_from_slice(&damage.instances);
+        }
+        self.cell_size = frame.cell_size;
+        self.backgrounds = frame.backgrounds;
+        self.overlays = frame.overlays;
+        self.background = Rgba(frame.background);
+    }
+
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        if self.surface.is_none() {
+            return Ok(());
+        }
+        let image = self.draw();
+        let surface = self.surface.as_mut().unwrap();
+
+        let mut buffer = surface.buffer_mut().map_err(|_| wgpu::SurfaceError::Lost)?;
+        // softbuffer expects 0RGB pixels
+        for (dst, src) in buffer.iter_mut().zip(image.pixels()) {
+            *dst = (src[0] as u32) << 16 | (src[1] as u32) << 8 | src[2] as u32;
+        }
+        buffer.present().map_err(|_| wgpu::SurfaceError::Lost)
+    }
+
+    fn render_to_image(&self) -> RgbaImage {
+        self.draw()
+    }
+
+    fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        let (Some(width), Some(height)) = (
+            NonZeroU32::new(new_size.width),
+            NonZeroU32::new(new_size.height),
+        ) else {
+            return;
+        };
+        self.size = new_size;
+        if let Some(surface) = self.surface.as_mut() {
+            if let Err(e) = surface.resize(width, height) {
+                tracing::warn!("failed to resize software surface: {e}");
+            }
+        }
+    }
+
+    fn size(&self) -> PhysicalSize<u32> {
+        self.size
+    }
+
+    fn window(&self) -> Option<&Arc<Window>> {
+        self.window.as_ref()
+    }
+}
+
+fn blend_colors(bg: Rgba<u8>, fg: Rgba<u8>, intensity: f32) -> Rgba<u8> {
+    let alpha = intensity; // Use glyph intensity as alpha
+    let inv_alpha = 1.0 - alpha;
+
+    Rgba([
+        (fg[0] as f32 * alpha + bg[0] as f32 * inv_alpha) as u8,
+        (fg[1] as f32 * alpha + bg[1] as f32 * inv_alpha) as u8,
+        (fg[2] as f32 * alpha + bg[2] as f32 * inv_alpha) as u8,
+        255,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::InstanceDamage;
+    use crate::text::AtlasUpdate;
+    use rusttype::{point, Rect};
+
+    const BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const CURSOR: [u8; 4] = [0, 255, 0, 255];
+
+    /// A 4x2 grid of 2x3 pixel cells
+    fn frame() -> RenderFrame {
+        RenderFrame {
+            cells: 8,
+            background: BACKGROUND,
+            cell_size: [2, 3],
+            backgrounds: vec![BackgroundInstance {
+                cell: [1, 0],
+                width: 2,
+                color: RED,
+            }],
+            overlays: vec![RectInstance {
+                rect: [0, 3, 2, 3],
+                color: CURSOR,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn render(frame: RenderFrame) -> RgbaImage {
+        let mut state = SoftwareState::new_headless(PhysicalSize::new(8, 6));
+        state.rerender_state(frame);
+        state.render_to_image()
+    }
+
+    #[test]
+    fn paints_cell_backgrounds() {
+        let image = render(frame());
+
+        assert_eq!(image.dimensions(), (8, 6));
+        assert_eq!(*image.get_pixel(0, 0), Rgba(BACKGROUND));
+        // The run covers columns 1 and 2 of the first line
+        for x in 2..6 {
+            for y in 0..3 {
+                assert_eq!(*image.get_pixel(x, y), Rgba(RED));
+            }
+        }
+        assert_eq!(*image.get_pixel(6, 0), Rgba(BACKGROUND));
+        assert_eq!(*image.get_pixel(2, 3), Rgba(BACKGROUND));
+    }
+
+    #[test]
+    fn blends_the_cursor_over_the_cells() {
+        let image = render(frame());
+        assert_eq!(*image.get_pixel(0, 3), Rgba(CURSOR));
+        assert_eq!(*image.get_pixel(1, 5), Rgba(CURSOR));
+        assert_eq!(*image.get_pixel(2, 3), Rgba(BACKGROUND));
+
+        // A transparent cursor leaves the cell as it was
+        let mut frame = frame();
+        frame.overlays[0].color = [0, 255, 0, 0];
+        let image = render(frame);
+        assert_eq!(*image.get_pixel(0, 3), Rgba(BACKGROUND));
+    }
+
+    #[test]
+    fn draws_glyphs_from_the_atlas() {
+        let mut frame = frame();
+        frame.atlas_updates = vec![AtlasUpdate {
+            rect: Rect {
+                min: point(0, 0),
+                max: point(1, 1),
+            },
+            data: vec![255],
+        }];
+        frame.damage = vec![InstanceDamage {
+            offset: 3,
+            instances: vec![CellInstance {
+                cell: [3, 0],
+                // A one pixel glyph, one pixel in from the top left of the cell
+                glyph_rect: [1, 1, 1, 1],
+                atlas_rect: [0, 0, 1, 1],
+                fg: [255, 255, 255, 255],
+                flags: CellInstance::GLYPH,
+                ..Default::default()
+            }],
+        }];
+        let image = render(frame);
+
+        assert_eq!(*image.get_pixel(7, 1), Rgba([255, 255, 255, 255]));
+        assert_eq!(*image.get_pixel(6, 0), Rgba(BACKGROUND));
+    }
+}
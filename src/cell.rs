@@ -0,0 +1,65 @@
+use std::ops::BitOr;
+use term::data::Color;
+
+/// SGR attributes of a cell, combined with `|`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Attribute(u16);
+
+impl Attribute {
+    pub const BOLD: Attribute = Attribute(1);
+    pub const DIM: Attribute = Attribute(1 << 1);
+    pub const ITALIC: Attribute = Attribute(1 << 2);
+    pub const UNDERLINE: Attribute = Attribute(1 << 3);
+    pub const BLINK: Attribute = Attribute(1 << 4);
+    pub const INVERSE: Attribute = Attribute(1 << 5);
+    pub const HIDDEN: Attribute = Attribute(1 << 6);
+    pub const STRIKETHROUGH: Attribute = Attribute(1 << 7);
+
+    /// Whether every attribute of `other` is set
+    pub fn contains(self, other: Attribute) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Attribute) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Attribute) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Attribute {
+    type Output = Attribute;
+
+    fn bitor(self, rhs: Attribute) -> Attribute {
+        Attribute(self.0 | rhs.0)
+    }
+}
+
+/// A character on the grid with the colors and attributes it is drawn with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    /// `None` is the default color, see [`crate::palette::DynamicColor`]
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub attr: Attribute,
+    /// Whether selective erase may clear the cell
+    pub erasable: bool,
+    /// Changed since the last frame
+    pub dirty: bool,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            fg: None,
+            bg: None,
+            attr: Attribute::default(),
+            erasable: true,
+            dirty: false,
+        }
+    }
+}
@@ -1,32 +1,153 @@
-use crate::Terminal;
+use crate::cell::Cell;
+use crate::input::KeyboardModes;
+use crate::modes::{AnsiMode, PrivateMode};
+use crate::palette::{color_spec, parse_color, DynamicColor, Palette};
+use crate::{GraphicState, Terminal};
 use rusttype::Scale;
+use std::path::{Path, PathBuf};
 use term::data::cursor::Cursor;
 use term::data::grids::GridIterator;
-use term::data::{Cell, Column, Line, RGBA};
+use term::data::{Column, Line};
 use vte::ansi::{
     Audible, ControlFunction, Editing, GraphicCharset, Management, Synchronization, TextProc,
     Visual,
 };
 use vte::{Handler, VtConsume};
 
+/// DA1 reply, a VT220 with ANSI colors
+const PRIMARY_DEVICE_ATTRIBUTES: &str = "\x1b[?62;22c";
+/// DA3 reply, the unit id is all zeros
+const TERTIARY_DEVICE_ATTRIBUTES: &str = "\x1bP!|00000000\x1b\\";
+/// XTVERSION reply
+const TERMINAL_VERSION: &str = concat!(
+    "\x1bP>|",
+    env!("CARGO_PKG_NAME"),
+    "(",
+    env!("CARGO_PKG_VERSION"),
+    ")\x1b\\"
+);
+/// Longest DCS string that is kept, the rest is dropped
+const MAX_DCS_LEN: usize = 1024;
+/// Most titles kept by XTWINOPS 22, older ones are dropped
+const MAX_TITLE_STACK: usize = 10;
+
+/// Shape of the text cursor, see DECSCUSR
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CursorShape {
+    #[default]
+    Block,
+    Underline,
+    Bar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blinking: bool,
+}
+
+impl Default for CursorStyle {
+    fn default() -> Self {
+        Self {
+            shape: CursorShape::Block,
+            blinking: true,
+        }
+    }
+}
+
+impl CursorStyle {
+    /// Style selected by `CSI Ps SP q`
+    pub fn from_decscusr(ps: usize) -> Self {
+        let (shape, blinking) = match ps {
+            2 => (CursorShape::Block, false),
+            3 => (CursorShape::Underline, true),
+            4 => (CursorShape::Underline, false),
+            5 => (CursorShape::Bar, true),
+            6 => (CursorShape::Bar, false),
+            _ => (CursorShape::Block, true),
+        };
+        Self { shape, blinking }
+    }
+
+    /// `Ps` of the DECSCUSR sequence that selects this style
+    pub fn decscusr(self) -> usize {
+        match (self.shape, self.blinking) {
+            (CursorShape::Block, true) => 1,
+            (CursorShape::Block, false) => 2,
+            (CursorShape::Underline, true) => 3,
+            (CursorShape::Underline, false) => 4,
+            (CursorShape::Bar, true) => 5,
+            (CursorShape::Bar, false) => 6,
+        }
+    }
+}
+
+/// A DCS string being received
 #[derive(Debug)]
-pub struct Display<'config> {
+struct DcsString {
+    intermediates: Vec<u8>,
+    action: char,
+    data: Vec<u8>,
+}
+
+/// State saved by DECSC and brought back by DECRC
+#[derive(Debug, Clone)]
+struct SavedCursor {
     cursor: Cursor,
-    saved_cursor: Option<Cursor>,
+    graphic: GraphicState,
+    /// DECOM
+    origin: bool,
+}
 
-    pub term: Terminal<'config>,
+#[derive(Debug)]
+pub struct Display {
+    cursor: Cursor,
+    saved_cursor: Option<SavedCursor>,
+    /// Shape of the cursor, whether it blinks is kept in the mode table
+    cursor_style: CursorStyle,
+    /// A BEL was received and not taken yet
+    bell: bool,
+    /// Replies to queries that have to be written back to the shell
+    responses: Vec<u8>,
+    dcs: Option<DcsString>,
+
+    /// Window title set with OSC 0 or 2
+    title: Option<String>,
+    /// Set with OSC 0 or 1, there is nothing to show it in
+    icon_name: Option<String>,
+    /// The title changed and the window doesn't show it yet
+    title_changed: bool,
+    /// Titles pushed with XTWINOPS 22
+    title_stack: Vec<Option<String>>,
+    icon_name_stack: Vec<Option<String>>,
+    /// Working directory of the shell, reported with OSC 7
+    working_directory: Option<PathBuf>,
+    /// A color of the palette changed and the renderer doesn't have it yet
+    palette_changed: bool,
+
+    pub term: Terminal,
 }
 
-impl<'config> Display<'config> {
+impl Display {
     pub fn resize(&mut self, x: u32, y: u32, scale: Scale) {
         let line_height: u32 = scale.y.round() as u32;
         let text_width: u32 = (scale.x / 2.0).round() as u32;
         let max_col = x / text_width;
         let max_row = y / line_height;
 
-        self.term.resize(max_row as usize, max_col as usize);
+        // The cursor saved by 1049 points into the hidden primary screen
+        let saved = match self.term.alt_screen() {
+            true => self.saved_cursor.as_mut().map(|saved| &mut saved.cursor),
+            false => None,
+        };
+        self.term
+            .resize(max_row as usize, max_col as usize, &mut self.cursor, saved);
+        if let Some(saved) = self.saved_cursor.as_mut() {
+            saved.cursor.line = Line(saved.cursor.line.0.min(self.term.lines() - 1));
+            saved.cursor.column = Column(saved.cursor.column.0.min(self.term.columns() - 1));
+        }
     }
-    pub fn new(x: u32, y: u32, scale: Scale, colorscheme: &'config [RGBA; 16]) -> Self {
+    pub fn new(x: u32, y: u32, scale: Scale, palette: Palette) -> Self {
         let line_height: u32 = scale.y.round() as u32;
         let text_width: u32 = (scale.x / 2.0).round() as u32;
         let max_col = x / text_width;
@@ -34,7 +155,18 @@ impl<'config> Display<'config> {
         Self {
             cursor: Cursor::new(Line(0), Column(0)),
             saved_cursor: None,
-            term: Terminal::new(max_row as usize, max_col as usize, colorscheme),
+            cursor_style: CursorStyle::default(),
+            bell: false,
+            responses: Vec::new(),
+            dcs: None,
+            title: None,
+            icon_name: None,
+            title_changed: false,
+            title_stack: Vec::new(),
+            icon_name_stack: Vec::new(),
+            working_directory: None,
+            palette_changed: false,
+            term: Terminal::new(max_row as usize, max_col as usize, palette),
         }
     }
 
@@ -44,22 +176,391 @@ impl<'config> Display<'config> {
             .grid_iter((start, Column(0)), (Line(80), Column(132)))
     }
 
+    /// The cursor and its style, `None` when it is hidden
+    pub fn cursor(&self) -> Option<(&Cursor, CursorStyle)> {
+        self.term
+            .modes
+            .private(PrivateMode::CursorVisible)
+            .then_some((&self.cursor, self.cursor_style()))
+    }
+
+    fn cursor_style(&self) -> CursorStyle {
+        CursorStyle {
+            blinking: self.term.modes.private(PrivateMode::CursorBlink),
+            ..self.cursor_style
+        }
+    }
+
+    /// Apply DEC private modes set with `CSI ? Pm h` or reset with `CSI ? Pm l`
+    fn set_private_modes(&mut self, codes: &[u16], enable: bool) {
+        for &code in codes {
+            let Some(mode) = PrivateMode::from_code(code) else {
+                tracing::debug!("ignored private mode {code}");
+                continue;
+            };
+            // Leaving the alternate screen has to know which mode entered it
+            let alt_screen = self.term.alt_screen();
+            self.term.modes.set_private(mode, enable);
+
+            match mode {
+                PrivateMode::Origin => self.goto(0, 0),
+                PrivateMode::AltScreen => {
+                    self.flush_for_move();
+                    self.term.set_alt_screen(enable);
+                }
+                // The alternate screen is cleared when leaving it
+                PrivateMode::AltScreenClear => {
+                    self.flush_for_move();
+                    if !enable && alt_screen {
+                        self.term.clear_screen();
+                    }
+                    self.term.set_alt_screen(enable);
+                }
+                PrivateMode::SaveCursor if enable => self.save_cursor(),
+                PrivateMode::SaveCursor => self.restore_cursor(),
+                // Save the cursor and switch to a cleared alternate screen,
+                // the cursor comes back when leaving it
+                PrivateMode::AltScreenSaveCursor => {
+                    self.flush_for_move();
+                    if enable {
+                        self.save_cursor();
+                        self.term.set_alt_screen(true);
+                        self.term.clear_screen();
+                    } else if alt_screen {
+                        self.term.set_alt_screen(false);
+                        self.restore_cursor();
+                    }
+                }
+                // Only kept in the mode table
+                _ => {}
+            }
+        }
+    }
+
+    /// Apply ANSI modes set with `CSI Pm h` or reset with `CSI Pm l`
+    fn set_ansi_modes(&mut self, codes: &[u16], enable: bool) {
+        for &code in codes {
+            match AnsiMode::from_code(code) {
+                Some(mode) => {
+                    self.write_pending();
+                    self.term.modes.set_ansi(mode, enable);
+                }
+                None => tracing::debug!("ignored ansi mode {code}"),
+            }
+        }
+    }
+
+    /// Queue a reply to be written to the shell
+    fn respond(&mut self, reply: &str) {
+        self.responses.extend_from_slice(reply.as_bytes());
+    }
+
+    /// CPR, the cursor position counted from one, relative to the scroll region in origin mode
+    fn report_cursor_position(&mut self) {
+        self.write_pending();
+        let top = match self.term.modes.private(PrivateMode::Origin) {
+            true => self.term.scroll_region().start,
+            false => 0,
+        };
+        let line = self.cursor.line.0.saturating_sub(top) + 1;
+        let col = self.cursor.column.0 + 1;
+        self.respond(&format!("\x1b[{line};{col}R"));
+    }
+
+    /// DA2 reply, a VT220 with the version as `major * 10000 + minor * 100 + patch`
+    fn report_secondary_attributes(&mut self) {
+        let version = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]
+        .iter()
+        .fold(0, |version, part| {
+            version * 100 + part.parse::<u32>().unwrap_or(0)
+        });
+        self.respond(&format!("\x1b[>1;{version};0c"));
+    }
+
+    /// DECRQSS, answer with the sequence that would restore the requested setting
+    fn report_setting(&mut self, setting: &[u8]) {
+        let reply = match setting {
+            b"m" => Some(format!("{}m", self.term.graphic_state_sgr())),
+            b"r" => {
+                let region = self.term.scroll_region();
+                Some(format!("{};{}r", region.start + 1, region.end))
+            }
+            b" q" => Some(format!("{} q", self.cursor_style().decscusr())),
+            _ => None,
+        };
+        match reply {
+            Some(reply) => self.respond(&format!("\x1bP1$r{reply}\x1b\\")),
+            None => self.respond("\x1bP0$r\x1b\\"),
+        }
+    }
+
+    /// Handle a complete DCS string
+    fn dcs_dispatch(&mut self, dcs: DcsString) {
+        match (dcs.intermediates.as_slice(), dcs.action) {
+            (b"$", 'q') => self.report_setting(&dcs.data),
+            _ => tracing::debug!("ignored dcs {:?} {}", dcs.intermediates, dcs.action),
+        }
+    }
+
+    /// Window title set by the shell, `None` until it sets one
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Whether the title changed since the last call
+    pub fn take_title_changed(&mut self) -> bool {
+        std::mem::take(&mut self.title_changed)
+    }
+
+    /// Last working directory the shell reported
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_deref()
+    }
+
+    /// Whether the palette changed since the last call
+    pub fn take_palette_changed(&mut self) -> bool {
+        std::mem::take(&mut self.palette_changed)
+    }
+
+    /// OSC 4, `index;color` pairs that set a palette color or query it when the color is `?`
+    fn set_indexed_colors(&mut self, args: &[Vec<u8>]) {
+        for pair in args.chunks(2) {
+            let [index, spec] = pair else {
+                tracing::debug!("osc 4 without a color");
+                return;
+            };
+            let Some(index) = palette_index(index) else {
+                tracing::debug!("ignored palette index {}", String::from_utf8_lossy(index));
+                continue;
+            };
+            if spec == b"?" {
+                let color = color_spec(self.term.palette.color(index));
+                self.respond(&format!("\x1b]4;{index};{color}\x1b\\"));
+            } else if let Some(color) = parse_color(spec) {
+                self.term.palette.set_color(index, color);
+                self.palette_damaged();
+            } else {
+                tracing::debug!("ignored color {}", String::from_utf8_lossy(spec));
+            }
+        }
+    }
+
+    /// OSC 10 to 12, every color after the first goes to the next dynamic color,
+    /// so `OSC 10 ; fg ; bg` sets both
+    fn set_dynamic_colors(&mut self, first: DynamicColor, args: &[Vec<u8>]) {
+        for (code, spec) in (first.code()..).zip(args) {
+            let Some(target) = DynamicColor::from_code(code) else {
+                return;
+            };
+            if spec == b"?" {
+                let color = color_spec(self.term.palette.dynamic(target));
+                self.respond(&format!("\x1b]{code};{color}\x1b\\"));
+            } else if let Some(color) = parse_color(spec) {
+                self.term.palette.set_dynamic(target, color);
+                self.palette_damaged();
+            } else {
+                tracing::debug!("ignored color {}", String::from_utf8_lossy(spec));
+            }
+        }
+    }
+
+    /// OSC 104, reset the given palette colors or all of them without an index
+    fn reset_indexed_colors(&mut self, args: &[Vec<u8>]) {
+        let indexes: Vec<_> = args.iter().filter(|arg| !arg.is_empty()).collect();
+        if indexes.is_empty() {
+            self.term.palette.reset_colors();
+        }
+        for index in indexes {
+            match palette_index(index) {
+                Some(index) => self.term.palette.reset_color(index),
+                None => tracing::debug!("ignored palette index {}", String::from_utf8_lossy(index)),
+            }
+        }
+        self.palette_damaged();
+    }
+
+    /// OSC 110 to 112
+    fn reset_dynamic_color(&mut self, color: DynamicColor) {
+        self.term.palette.reset_dynamic(color);
+        self.palette_damaged();
+    }
+
+    /// Every cell can use a changed color, so the whole screen is drawn again
+    fn palette_damaged(&mut self) {
+        self.palette_changed = true;
+        self.term.damage_all();
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.title_changed |= self.title != title;
+        self.title = title;
+    }
+
+    /// XTWINOPS, only pushing and popping the title is supported
+    fn window_ops(&mut self, params: &[u16]) {
+        // 0 is the title and the icon name, 1 the icon name and 2 the title
+        let (icon, title) = match params.get(1).copied().unwrap_or(0) {
+            0 => (true, true),
+            1 => (true, false),
+            2 => (false, true),
+            _ => return,
+        };
+        match params.first() {
+            Some(22) => {
+                if icon {
+                    push_bounded(&mut self.icon_name_stack, self.icon_name.clone());
+                }
+                if title {
+                    push_bounded(&mut self.title_stack, self.title.clone());
+                }
+            }
+            Some(23) => {
+                if icon {
+                    if let Some(icon_name) = self.icon_name_stack.pop() {
+                        self.icon_name = icon_name;
+                    }
+                }
+                if title {
+                    if let Some(title) = self.title_stack.pop() {
+                        self.set_title(title);
+                    }
+                }
+            }
+            _ => tracing::debug!("ignored window op {params:?}"),
+        }
+    }
+
+    /// Replies queued since the last call
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
+    }
+
+    /// Move the cursor to a zero based position, clamped to the grid.
+    /// In origin mode the line is relative to the scroll region and can't leave it.
+    fn goto(&mut self, line: usize, col: usize) {
+        self.flush_for_move();
+        let bounds = if self.term.modes.private(PrivateMode::Origin) {
+            self.term.scroll_region()
+        } else {
+            0..self.term.lines()
+        };
+        self.cursor.line = Line((bounds.start + line).min(bounds.end - 1));
+        self.cursor.column = Column(col.min(self.term.columns() - 1));
+    }
+
+    /// Move the cursor to a zero based line, keeping the column
+    fn goto_line(&mut self, line: usize) {
+        let col = self.cursor.column.0;
+        self.goto(line, col);
+    }
+
+    /// Move the cursor to a zero based column, keeping the line
+    fn goto_col(&mut self, col: usize) {
+        self.flush_for_move();
+        self.cursor.column = Column(col.min(self.term.columns() - 1));
+    }
+
+    /// Move the cursor up or down, a cursor inside the scroll region stops at its margins
+    fn move_lines(&mut self, delta: isize) {
+        self.flush_for_move();
+        let region = self.term.scroll_region();
+        let line = self.cursor.line.0;
+        let (top, bottom) = if region.contains(&line) {
+            (region.start, region.end - 1)
+        } else {
+            (0, self.term.lines() - 1)
+        };
+        self.cursor.line = Line(line.saturating_add_signed(delta).clamp(top, bottom));
+    }
+
+    /// Write the cells waiting in the terminal at the cursor. A pending wrap is kept,
+    /// the next character still goes to the next line.
+    pub fn write_pending(&mut self) {
+        self.term.update(&mut self.cursor);
+    }
+
+    /// Write the cells waiting in the terminal before the cursor moves,
+    /// which drops a pending wrap. Everything that leaves the cursor where it is
+    /// only calls [`Self::write_pending`].
+    fn flush_for_move(&mut self) {
+        self.write_pending();
+        self.term.clear_wrap_pending();
+    }
+
+    /// Move the cursor down a line, scrolling the region up when the cursor is on its bottom margin
+    fn index(&mut self) {
+        self.flush_for_move();
+        self.term.index(&mut self.cursor);
+    }
+
+    /// Move the cursor up a line, scrolling the region down when the cursor is on its top margin
+    fn reverse_index(&mut self) {
+        self.flush_for_move();
+        let line = self.cursor.line.0;
+        if line == self.term.scroll_region().start {
+            self.term.scroll_down(1);
+        } else if line > 0 {
+            self.cursor.line.0 -= 1;
+        }
+    }
+
+    /// Move the cursor over `stops` tab stops, backwards when negative
+    fn tab(&mut self, stops: isize) {
+        self.flush_for_move();
+        for _ in 0..stops.unsigned_abs() {
+            let col = self.cursor.column.0;
+            self.cursor.column = Column(if stops > 0 {
+                self.term.next_tab_stop(col)
+            } else {
+                self.term.previous_tab_stop(col)
+            });
+        }
+    }
+
+    /// Move the cursor left or right, stopping at the edges of the grid
+    fn move_cols(&mut self, delta: isize) {
+        self.flush_for_move();
+        let col = self.cursor.column.0.saturating_add_signed(delta);
+        self.cursor.column = Column(col.min(self.term.columns() - 1));
+    }
+
+    pub fn keyboard_modes(&self) -> KeyboardModes {
+        let modes = &self.term.modes;
+        KeyboardModes {
+            app_cursor: modes.private(PrivateMode::CursorKeys),
+            app_keypad: modes.private(PrivateMode::ApplicationKeypad),
+            new_line: modes.ansi(AnsiMode::LineFeedNewLine),
+        }
+    }
+
+    /// LF, VT and FF, which also return the cursor in LNM
+    fn line_feed(&mut self) {
+        self.index();
+        if self.term.modes.ansi(AnsiMode::LineFeedNewLine) {
+            self.cursor.column = Column(0);
+        }
+    }
+
     fn execute_control(&mut self, control: ControlFunction) {
         match control {
             ControlFunction::Null => {}
             ControlFunction::Enquire => {}
-            ControlFunction::Audible(Audible::Bell) => {}
-            ControlFunction::TextProc(TextProc::Backspace) => {}
-            ControlFunction::TextProc(TextProc::HTab) => {}
-            ControlFunction::TextProc(TextProc::LineFeed) => {
-                self.term.update(&mut self.cursor);
-                self.cursor.column.0 = 0;
-                self.cursor.line.0 += 1;
+            ControlFunction::Audible(Audible::Bell) => self.bell = true,
+            ControlFunction::TextProc(TextProc::Backspace) => {
+                self.flush_for_move();
+                self.cursor.column.0 = self.cursor.column.0.saturating_sub(1);
             }
-            ControlFunction::TextProc(TextProc::VTab) => {}
-            ControlFunction::TextProc(TextProc::FormFeed) => {}
+            ControlFunction::TextProc(TextProc::HTab) => self.tab(1),
+            ControlFunction::TextProc(TextProc::LineFeed) => self.line_feed(),
+            // VT and FF are handled like LF
+            ControlFunction::TextProc(TextProc::VTab) => self.line_feed(),
+            ControlFunction::TextProc(TextProc::FormFeed) => self.line_feed(),
             ControlFunction::TextProc(TextProc::CarriageReturn) => {
-                self.term.update(&mut self.cursor);
+                self.flush_for_move();
                 self.cursor.column.0 = 0;
             }
             ControlFunction::Graphic(GraphicCharset::LockingShift1) => {}
@@ -68,35 +569,106 @@ impl<'config> Display<'config> {
             ControlFunction::Synchronization(Synchronization::XOFF) => {}
             ControlFunction::Cancel => {}
             ControlFunction::Substitute => {}
-            ControlFunction::TextProc(TextProc::Index) => {}
-            ControlFunction::TextProc(TextProc::NextLine) => {}
-            ControlFunction::TextProc(TextProc::SetHTab) => {}
-            ControlFunction::TextProc(TextProc::ReverseIndex) => {
-                self.cursor.line.0 -= 1;
+            ControlFunction::TextProc(TextProc::Index) => self.index(),
+            ControlFunction::TextProc(TextProc::NextLine) => {
+                self.index();
+                self.cursor.column.0 = 0;
+            }
+            ControlFunction::TextProc(TextProc::SetHTab) => {
+                self.write_pending();
+                self.term.set_tab_stop(self.cursor.column.0);
             }
+            ControlFunction::TextProc(TextProc::ReverseIndex) => self.reverse_index(),
             ControlFunction::Graphic(GraphicCharset::SingleShift2) => {}
             ControlFunction::Graphic(GraphicCharset::SingleShift3) => {}
             ControlFunction::StringTerminator => {}
-            ControlFunction::TextProc(TextProc::BackIndex) => {}
-            ControlFunction::TextProc(TextProc::SaveCursor) => {}
-            ControlFunction::TextProc(TextProc::RestoreCursor) => {}
-            ControlFunction::TextProc(TextProc::ForwardIndex) => {}
-            ControlFunction::Management(Management::Reset) => {}
+            // DECBI and DECFI scroll the columns when the cursor is on the edge of the screen
+            ControlFunction::TextProc(TextProc::BackIndex) => {
+                self.flush_for_move();
+                match self.cursor.column.0 {
+                    0 => self.term.scroll_columns_right(1),
+                    _ => self.cursor.column.0 -= 1,
+                }
+            }
+            ControlFunction::TextProc(TextProc::ForwardIndex) => {
+                self.flush_for_move();
+                if self.cursor.column.0 + 1 >= self.term.columns() {
+                    self.term.scroll_columns_left(1);
+                } else {
+                    self.cursor.column.0 += 1;
+                }
+            }
+            ControlFunction::TextProc(TextProc::SaveCursor) => self.save_cursor(),
+            ControlFunction::TextProc(TextProc::RestoreCursor) => self.restore_cursor(),
+            ControlFunction::Management(Management::Reset) => self.reset(),
+            ControlFunction::Management(Management::KeypadApplication) => {
+                self.term
+                    .modes
+                    .set_private(PrivateMode::ApplicationKeypad, true);
+            }
+            ControlFunction::Management(Management::KeypadNumeric) => {
+                self.term
+                    .modes
+                    .set_private(PrivateMode::ApplicationKeypad, false);
+            }
             ControlFunction::Visual(Visual::DoubleTop) => {}
             ControlFunction::Visual(Visual::DoubleBottom) => {}
             ControlFunction::Visual(Visual::SingleWidth) => {}
             ControlFunction::Visual(Visual::DoubleWidth) => {}
             ControlFunction::Illegal => {}
-            _ => unreachable!(),
+            other => tracing::debug!("ignored control function {other:?}"),
+        }
+    }
+
+    /// DECSC, save the cursor together with the rendition and origin mode
+    fn save_cursor(&mut self) {
+        self.write_pending();
+        self.saved_cursor = Some(SavedCursor {
+            cursor: self.cursor.clone(),
+            graphic: self.term.graphic_state(),
+            origin: self.term.modes.private(PrivateMode::Origin),
+        });
+    }
+
+    /// DECRC, without a saved cursor the cursor goes home with the default rendition
+    fn restore_cursor(&mut self) {
+        self.flush_for_move();
+        match self.saved_cursor.clone() {
+            Some(saved) => {
+                self.cursor = saved.cursor;
+                self.term.set_graphic_state(saved.graphic);
+                self.term
+                    .modes
+                    .set_private(PrivateMode::Origin, saved.origin);
+            }
+            None => {
+                self.cursor = Cursor::new(Line(0), Column(0));
+                self.term.reset_graphic();
+                self.term.modes.set_private(PrivateMode::Origin, false);
+            }
         }
     }
 
+    /// RIS, bring the terminal back to the state it started in
+    fn reset(&mut self) {
+        self.term.reset();
+        self.palette_changed = true;
+        self.cursor = Cursor::new(Line(0), Column(0));
+        self.saved_cursor = None;
+        self.cursor_style = CursorStyle::default();
+    }
+
+    /// Whether a BEL was received since the last call
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell)
+    }
+
     fn add_new_cell(&mut self, c: char) {
         self.term.add_new_cell(c)
     }
 }
 
-impl Handler for Display<'_> {
+impl Handler for Display {
     fn print(&mut self, consume: vte::VtConsume) {
         match consume {
             VtConsume::Print(c) => self.add_new_cell(c),
@@ -119,128 +691,247 @@ impl Handler for Display<'_> {
             ControlFunction::Visual(v) => match v {
                 Visual::DarkMode(d) => self.term.dark_mode = d,
                 Visual::GraphicRendition(vec) => {
-                    self.term.update(&mut self.cursor);
+                    self.write_pending();
                     self.term.rendition(vec)
                 }
+                Visual::CursorStyle(ps) => {
+                    self.cursor_style = CursorStyle::from_decscusr(ps as usize);
+                    self.term
+                        .modes
+                        .set_private(PrivateMode::CursorBlink, self.cursor_style.blinking);
+                }
                 _ => {}
             },
-            ControlFunction::Editing(e) => match e {
-                Editing::DeleteCharacter(_) => {}
-                Editing::DeleteCol(_) => {}
-                Editing::DeleteLine(_) => {}
-                Editing::EraseInDisplay(flag) => match flag {
-                    0 => {
-                        let col = self.cursor.column;
-                        let line = self.cursor.line;
-                        // Clear the current line first
-                        let row_len = self.term.data[line].len();
-                        let col_len = self.term.data.len();
-                        self.term
-                            .erase_line_range_unchecked(line, col.0 + 1..row_len, |_| true);
-
-                        self.term
-                            .erase_range_unchecked((line.0 + 1)..col_len, |_| true);
-                    }
-                    1 => {
-                        let col = self.cursor.column;
-                        let line = self.cursor.line;
-                        // Clear from the top of the display
-
-                        self.term.erase_range_unchecked(0..line.0, |_| true);
-                        self.term
-                            .erase_line_range_unchecked(line, 0..col.0, |_| true);
-                    }
-                    2 => {
-                        let col_len = self.term.data.len();
-                        self.term.erase_range_unchecked(0..col_len, |_| true);
-                    }
-                    _ => {}
-                },
-                Editing::SelectiveEraseDisplay(flag) => match flag {
-                    0 => {
-                        let col = self.cursor.column;
-                        let line = self.cursor.line;
-                        // Clear the current line first
-                        let row_len = self.term.data[line].len();
-                        let col_len = self.term.data.len();
-                        self.term
-                            .erase_line_range_unchecked(line, col.0 + 1..row_len, |c| c.erasable);
-
-                        self.term
-                            .erase_range_unchecked((line.0 + 1)..col_len, |c| c.erasable);
-                    }
-                    1 => {
-                        let col = self.cursor.column;
-                        let line = self.cursor.line;
-                        // Clear from the top of the display
-
-                        self.term.erase_range_unchecked(0..line.0, |c| c.erasable);
-                        self.term
-                            .erase_line_range_unchecked(line, 0..col.0, |c| c.erasable);
-                    }
-                    2 => {
-                        let col_len = self.term.data.len();
-                        self.term.erase_range_unchecked(0..col_len, |c| c.erasable);
-                    }
-                    _ => {}
-                },
-                Editing::EraseInLine(flag) => match flag {
-                    0 => {
-                        let row_len = self.term.data[self.cursor.line].len();
-                        self.term.erase_line_range_unchecked(
-                            self.cursor.line,
-                            self.cursor.column.0 + 1..row_len,
-                            |_| true,
-                        );
+            ControlFunction::Management(m) => match m {
+                Management::SetTopBottomMargins(top, bottom) => {
+                    self.write_pending();
+                    if self
+                        .term
+                        .set_scroll_region(count(top as usize) - 1, bottom as usize)
+                    {
+                        self.goto(0, 0);
                     }
-                    1 => {
-                        self.term.erase_line_range_unchecked(
-                            self.cursor.line,
-                            0..self.cursor.column.0 + 1,
-                            |_| true,
-                        );
-                    }
-                    2 => {
-                        let row_len = self.term.data[self.cursor.line].len();
-                        self.term
-                            .erase_line_range_unchecked(self.cursor.line, 0..row_len, |_| true);
+                }
+                Management::SetPrivateMode(modes) => self.set_private_modes(&modes, true),
+                Management::ResetPrivateMode(modes) => self.set_private_modes(&modes, false),
+                Management::SetMode(modes) => self.set_ansi_modes(&modes, true),
+                Management::ResetMode(modes) => self.set_ansi_modes(&modes, false),
+                Management::WindowOps(params) => self.window_ops(&params),
+                Management::PrimaryDeviceAttributes => self.respond(PRIMARY_DEVICE_ATTRIBUTES),
+                Management::SecondaryDeviceAttributes => self.report_secondary_attributes(),
+                Management::TertiaryDeviceAttributes => self.respond(TERTIARY_DEVICE_ATTRIBUTES),
+                Management::TerminalVersion => self.respond(TERMINAL_VERSION),
+                // DSR, 5 asks whether the terminal is fine and 6 for the cursor position
+                Management::DeviceStatusReport(5) => self.respond("\x1b[0n"),
+                Management::DeviceStatusReport(6) => self.report_cursor_position(),
+                // DECRQM
+                Management::RequestPrivateMode(code) => {
+                    let report = self.term.modes.report_private(code);
+                    self.respond(&format!("\x1b[?{code};{}$y", report as u8));
+                }
+                Management::RequestMode(code) => {
+                    let report = self.term.modes.report_ansi(code);
+                    self.respond(&format!("\x1b[{code};{}$y", report as u8));
+                }
+                _ => {}
+            },
+            ControlFunction::Editing(e) => match e {
+                Editing::ScrollUp(n) => {
+                    self.write_pending();
+                    self.term.scroll_up(count(n as usize));
+                }
+                Editing::ScrollDown(n) => {
+                    self.write_pending();
+                    self.term.scroll_down(count(n as usize));
+                }
+                Editing::InsertCharacter(n) => {
+                    self.write_pending();
+                    self.term.insert_chars(&self.cursor, count(n as usize));
+                }
+                Editing::DeleteCharacter(n) => {
+                    self.write_pending();
+                    self.term.delete_chars(&self.cursor, count(n as usize));
+                }
+                Editing::EraseCharacter(n) => {
+                    self.write_pending();
+                    self.term.erase_chars(&self.cursor, count(n as usize));
+                }
+                Editing::InsertCol(n) => {
+                    self.write_pending();
+                    self.term.insert_columns(&self.cursor, count(n as usize));
+                }
+                Editing::DeleteCol(n) => {
+                    self.write_pending();
+                    self.term.delete_columns(&self.cursor, count(n as usize));
+                }
+                // The cursor goes to the start of the line like xterm does
+                Editing::InsertLine(n) => {
+                    self.flush_for_move();
+                    self.term.insert_lines(&self.cursor, count(n as usize));
+                    self.cursor.column = Column(0);
+                }
+                Editing::DeleteLine(n) => {
+                    self.flush_for_move();
+                    self.term.delete_lines(&self.cursor, count(n as usize));
+                    self.cursor.column = Column(0);
+                }
+                Editing::EraseInDisplay(flag) => {
+                    self.write_pending();
+                    match flag {
+                        0 => {
+                            let col = self.cursor.column;
+                            let line = self.cursor.line;
+                            // Clear the current line first
+                            let row_len = self.term.data[line].len();
+                            let col_len = self.term.data.len();
+                            self.term
+                                .erase_line_range_unchecked(line, col.0 + 1..row_len, |_| true);
+
+                            self.term
+                                .erase_range_unchecked((line.0 + 1)..col_len, |_| true);
+                        }
+                        1 => {
+                            let col = self.cursor.column;
+                            let line = self.cursor.line;
+                            // Clear from the top of the display
+
+                            self.term.erase_range_unchecked(0..line.0, |_| true);
+                            self.term
+                                .erase_line_range_unchecked(line, 0..col.0, |_| true);
+                        }
+                        2 => {
+                            let col_len = self.term.data.len();
+                            self.term.erase_range_unchecked(0..col_len, |_| true);
+                        }
+                        _ => {}
                     }
-                    _ => {}
-                },
-                Editing::SelectiveEraseLine(flag) => match flag {
-                    0 => {
-                        let row_len = self.term.data[self.cursor.line].len();
-                        self.term.erase_line_range_unchecked(
-                            self.cursor.line,
-                            self.cursor.column.0..row_len,
-                            |c| c.erasable,
-                        );
+                }
+                Editing::SelectiveEraseDisplay(flag) => {
+                    self.write_pending();
+                    match flag {
+                        0 => {
+                            let col = self.cursor.column;
+                            let line = self.cursor.line;
+                            // Clear the current line first
+                            let row_len = self.term.data[line].len();
+                            let col_len = self.term.data.len();
+                            self.term
+                                .erase_line_range_unchecked(line, col.0 + 1..row_len, |c| {
+                                    c.erasable
+                                });
+
+                            self.term
+                                .erase_range_unchecked((line.0 + 1)..col_len, |c| c.erasable);
+                        }
+                        1 => {
+                            let col = self.cursor.column;
+                            let line = self.cursor.line;
+                            // Clear from the top of the display
+
+                            self.term.erase_range_unchecked(0..line.0, |c| c.erasable);
+                            self.term
+                                .erase_line_range_unchecked(line, 0..col.0, |c| c.erasable);
+                        }
+                        2 => {
+                            let col_len = self.term.data.len();
+                            self.term.erase_range_unchecked(0..col_len, |c| c.erasable);
+                        }
+                        _ => {}
                     }
-                    1 => {
-                        self.term.erase_line_range_unchecked(
-                            self.cursor.line,
-                            0..self.cursor.column.0,
-                            |c| c.erasable,
-                        );
+                }
+                Editing::EraseInLine(flag) => {
+                    self.write_pending();
+                    match flag {
+                        0 => {
+                            let row_len = self.term.data[self.cursor.line].len();
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                self.cursor.column.0 + 1..row_len,
+                                |_| true,
+                            );
+                        }
+                        1 => {
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                0..self.cursor.column.0 + 1,
+                                |_| true,
+                            );
+                        }
+                        2 => {
+                            let row_len = self.term.data[self.cursor.line].len();
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                0..row_len,
+                                |_| true,
+                            );
+                        }
+                        _ => {}
                     }
-                    2 => {
-                        let row_len = self.term.data[self.cursor.line].len();
-                        self.term
-                            .erase_line_range_unchecked(self.cursor.line, 0..row_len, |c| {
-                                c.erasable
-                            });
+                }
+                Editing::SelectiveEraseLine(flag) => {
+                    self.write_pending();
+                    match flag {
+                        0 => {
+                            let row_len = self.term.data[self.cursor.line].len();
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                self.cursor.column.0..row_len,
+                                |c| c.erasable,
+                            );
+                        }
+                        1 => {
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                0..self.cursor.column.0,
+                                |c| c.erasable,
+                            );
+                        }
+                        2 => {
+                            let row_len = self.term.data[self.cursor.line].len();
+                            self.term.erase_line_range_unchecked(
+                                self.cursor.line,
+                                0..row_len,
+                                |c| c.erasable,
+                            );
+                        }
+                        _ => {}
                     }
-                    _ => {}
-                },
+                }
                 _ => {}
             },
             ControlFunction::TextProc(t) => match t {
-                TextProc::SaveCursor | TextProc::SaveCursorPosition => {
-                    self.saved_cursor = Some(self.cursor.clone())
+                TextProc::SaveCursor | TextProc::SaveCursorPosition => self.save_cursor(),
+                TextProc::RestoreCursor | TextProc::RestoreSavedCursor => self.restore_cursor(),
+                TextProc::CursorUp(n) => self.move_lines(-(count(n as usize) as isize)),
+                TextProc::CursorDown(n) | TextProc::LinePositionRelative(n) => {
+                    self.move_lines(count(n as usize) as isize)
+                }
+                TextProc::CursorForward(n) | TextProc::CharacterPositionRelative(n) => {
+                    self.move_cols(count(n as usize) as isize)
+                }
+                TextProc::CursorBackward(n) => self.move_cols(-(count(n as usize) as isize)),
+                TextProc::CursorNextLine(n) => {
+                    self.move_lines(count(n as usize) as isize);
+                    self.cursor.column = Column(0);
                 }
-                TextProc::RestoreCursor | TextProc::RestoreSavedCursor => {
-                    if let Some(cursor) = self.saved_cursor.take() {
-                        self.cursor = cursor;
+                TextProc::CursorPrecedingLine(n) => {
+                    self.move_lines(-(count(n as usize) as isize));
+                    self.cursor.column = Column(0);
+                }
+                TextProc::CursorPosition(line, col)
+                | TextProc::CharacterAndLinePosition(line, col) => {
+                    self.goto(count(line as usize) - 1, count(col as usize) - 1)
+                }
+                TextProc::CharacterAbsolute(col) => self.goto_col(count(col as usize) - 1),
+                TextProc::LinePositionAbsolute(line) => self.goto_line(count(line as usize) - 1),
+                TextProc::CursorForwardTabulation(n) => self.tab(count(n as usize) as isize),
+                TextProc::CursorBackwardTabulation(n) => self.tab(-(count(n as usize) as isize)),
+                TextProc::TabulationClear(ps) => {
+                    self.write_pending();
+                    match ps {
+                        0 => self.term.clear_tab_stop(Some(self.cursor.column.0)),
+                        3 => self.term.clear_tab_stop(None),
+                        _ => {}
                     }
                 }
                 _ => {}
@@ -251,11 +942,105 @@ impl Handler for Display<'_> {
         }
     }
 
-    fn hook(&mut self, consume: vte::VtConsume) {}
+    fn hook(&mut self, consume: vte::VtConsume) {
+        if let VtConsume::Hook {
+            intermediates,
+            action,
+            ..
+        } = consume
+        {
+            self.dcs = Some(DcsString {
+                intermediates,
+                action,
+                data: Vec::new(),
+            });
+        }
+    }
+
+    fn put(&mut self, consume: vte::VtConsume) {
+        if let (Some(dcs), VtConsume::Put(byte)) = (self.dcs.as_mut(), consume) {
+            if dcs.data.len() < MAX_DCS_LEN {
+                dcs.data.push(byte);
+            }
+        }
+    }
+
+    fn unhook(&mut self) {
+        if let Some(dcs) = self.dcs.take() {
+            self.dcs_dispatch(dcs);
+        }
+    }
+
+    fn osc_dispatch(&mut self, consume: vte::VtConsume) {
+        let VtConsume::Osc(params) = consume else {
+            return;
+        };
+        let Some((command, args)) = params.split_first() else {
+            return;
+        };
+        // The text itself can contain `;`
+        let text = String::from_utf8_lossy(&args.join(&b';')).into_owned();
+
+        match command.as_slice() {
+            b"0" => {
+                self.icon_name = Some(text.clone());
+                self.set_title(Some(text));
+            }
+            b"1" => self.icon_name = Some(text),
+            b"2" => self.set_title(Some(text)),
+            b"7" => match file_url_path(&text) {
+                Some(path) => self.working_directory = Some(path),
+                None => tracing::debug!("ignored working directory {text}"),
+            },
+            b"4" => self.set_indexed_colors(args),
+            b"10" => self.set_dynamic_colors(DynamicColor::Foreground, args),
+            b"11" => self.set_dynamic_colors(DynamicColor::Background, args),
+            b"12" => self.set_dynamic_colors(DynamicColor::Cursor, args),
+            b"104" => self.reset_indexed_colors(args),
+            b"110" => self.reset_dynamic_color(DynamicColor::Foreground),
+            b"111" => self.reset_dynamic_color(DynamicColor::Background),
+            b"112" => self.reset_dynamic_color(DynamicColor::Cursor),
+            _ => tracing::debug!("ignored osc {}", String::from_utf8_lossy(command)),
+        }
+    }
+}
+
+/// Path of a `file://host/path` url sent with OSC 7, the host is not checked
+fn file_url_path(url: &str) -> Option<PathBuf> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
 
-    fn put(&mut self, consume: vte::VtConsume) {}
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [iter.next()?, iter.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+/// Index of the 256 color palette given to OSC 4 and 104
+fn palette_index(index: &[u8]) -> Option<usize> {
+    std::str::from_utf8(index)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|index| *index < 256)
+}
 
-    fn unhook(&mut self) {}
+/// Push to a stack that drops its oldest entry when it is full
+fn push_bounded<T>(stack: &mut Vec<T>, value: T) {
+    if stack.len() == MAX_TITLE_STACK {
+        stack.remove(0);
+    }
+    stack.push(value);
+}
 
-    fn osc_dispatch(&mut self, consume: vte::VtConsume) {}
+/// Numeric parameter of a sequence, where a missing or zero count means one
+fn count(n: usize) -> usize {
+    n.max(1)
 }
@@ -0,0 +1,366 @@
+use winit::event::KeyEvent;
+use winit::keyboard::{Key, KeyLocation, ModifiersState, NamedKey};
+
+/// Terminal modes that change what a key sends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyboardModes {
+    /// DECCKM, cursor keys send `SS3` instead of `CSI` sequences
+    pub app_cursor: bool,
+    /// DECKPAM, the keypad sends `SS3` sequences instead of its characters
+    pub app_keypad: bool,
+    /// LNM, Enter sends CR LF
+    pub new_line: bool,
+}
+
+/// Translate a key press into the bytes xterm would send for it,
+/// `None` when the key doesn't send anything.
+///
+/// * `mods`: Modifiers held while the key was pressed
+/// * `modes`: Keyboard modes set by the application
+pub fn encode_key(event: &KeyEvent, mods: ModifiersState, modes: KeyboardModes) -> Option<Vec<u8>> {
+    encode(
+        &event.logical_key,
+        event.location,
+        event.text.as_deref(),
+        mods,
+        modes,
+    )
+}
+
+/// [`encode_key`] with the parts of the [`KeyEvent`] it looks at
+fn encode(
+    key: &Key,
+    location: KeyLocation,
+    text: Option<&str>,
+    mods: ModifiersState,
+    modes: KeyboardModes,
+) -> Option<Vec<u8>> {
+    if location == KeyLocation::Numpad && modes.app_keypad {
+        if let Some(bytes) = encode_keypad(key) {
+            return Some(bytes);
+        }
+    }
+
+    match key {
+        Key::Named(named) => encode_named(*named, mods, modes),
+        Key::Character(c) => encode_text(c, mods),
+        _ => text.and_then(|text| encode_text(text, mods)),
+    }
+}
+
+/// Modifier parameter of xterm's `CSI 1 ; Pm X` sequences, 1 means no modifier
+fn modifier_param(mods: ModifiersState) -> u8 {
+    1 + mods.shift_key() as u8
+        + ((mods.alt_key() as u8) << 1)
+        + ((mods.control_key() as u8) << 2)
+        + ((mods.super_key() as u8) << 3)
+}
+
+fn encode_named(key: NamedKey, mods: ModifiersState, modes: KeyboardModes) -> Option<Vec<u8>> {
+    let param = modifier_param(mods);
+    let bytes = match key {
+        NamedKey::ArrowUp => cursor_key(b'A', param, modes),
+        NamedKey::ArrowDown => cursor_key(b'B', param, modes),
+        NamedKey::ArrowRight => cursor_key(b'C', param, modes),
+        NamedKey::ArrowLeft => cursor_key(b'D', param, modes),
+        NamedKey::Home => cursor_key(b'H', param, modes),
+        NamedKey::End => cursor_key(b'F', param, modes),
+        NamedKey::F1 => function_key(b'P', param),
+        NamedKey::F2 => function_key(b'Q', param),
+        NamedKey::F3 => function_key(b'R', param),
+        NamedKey::F4 => function_key(b'S', param),
+        NamedKey::Insert => tilde_key(2, param),
+        NamedKey::Delete => tilde_key(3, param),
+        NamedKey::PageUp => tilde_key(5, param),
+        NamedKey::PageDown => tilde_key(6, param),
+        NamedKey::F5 => tilde_key(15, param),
+        NamedKey::F6 => tilde_key(17, param),
+        NamedKey::F7 => tilde_key(18, param),
+        NamedKey::F8 => tilde_key(19, param),
+        NamedKey::F9 => tilde_key(20, param),
+        NamedKey::F10 => tilde_key(21, param),
+        NamedKey::F11 => tilde_key(23, param),
+        NamedKey::F12 => tilde_key(24, param),
+        NamedKey::Tab if mods.shift_key() => b"\x1b[Z".to_vec(),
+        NamedKey::Tab => alt_prefixed(b"\t", mods),
+        NamedKey::Enter if modes.new_line => alt_prefixed(b"\r\n", mods),
+        NamedKey::Enter => alt_prefixed(b"\r", mods),
+        NamedKey::Escape => alt_prefixed(b"\x1b", mods),
+        // Ctrl+Backspace sends BS so it can be told apart from DEL
+        NamedKey::Backspace if mods.control_key() => alt_prefixed(b"\x08", mods),
+        NamedKey::Backspace => alt_prefixed(b"\x7f", mods),
+        NamedKey::Space => return encode_text(" ", mods),
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// Arrows, Home and End, which honour DECCKM when no modifier is held
+fn cursor_key(final_byte: u8, param: u8, modes: KeyboardModes) -> Vec<u8> {
+    match (param, modes.app_cursor) {
+        (1, true) => vec![0x1b, b'O', final_byte],
+        (1, false) => vec![0x1b, b'[', final_byte],
+        _ => format!("\x1b[1;{param}{}", final_byte as char).into_bytes(),
+    }
+}
+
+/// F1 to F4, sent as `SS3` unless a modifier is held
+fn function_key(final_byte: u8, param: u8) -> Vec<u8> {
+    match param {
+        1 => vec![0x1b, b'O', final_byte],
+        _ => format!("\x1b[1;{param}{}", final_byte as char).into_bytes(),
+    }
+}
+
+/// Keys sent as `CSI Ps ~`
+fn tilde_key(code: u8, param: u8) -> Vec<u8> {
+    match param {
+        1 => format!("\x1b[{code}~").into_bytes(),
+        _ => format!("\x1b[{code};{param}~").into_bytes(),
+    }
+}
+
+/// Keypad keys in application keypad mode
+fn encode_keypad(key: &Key) -> Option<Vec<u8>> {
+    let final_byte = match key {
+        Key::Named(NamedKey::Enter) => b'M',
+        Key::Character(c) => match c.as_str() {
+            digit @ ("0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9") => {
+                b'p' + (digit.as_bytes()[0] - b'0')
+            }
+            "*" => b'j',
+            "+" => b'k',
+            "," => b'l',
+            "-" => b'm',
+            "." => b'n',
+            "/" => b'o',
+            "=" => b'X',
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(vec![0x1b, b'O', final_byte])
+}
+
+/// Printable text, Ctrl turns a single character into its control code
+/// and Alt prefixes the result with `ESC`
+fn encode_text(text: &str, mods: ModifiersState) -> Option<Vec<u8>> {
+    if text.is_empty() {
+        return None;
+    }
+
+    let mut chars = text.chars();
+    let bytes = match (chars.next(), chars.next()) {
+        (Some(c), None) if mods.control_key() => {
+            control_code(c).map_or_else(|| text.as_bytes().to_vec(), |code| vec![code])
+        }
+        _ => text.as_bytes().to_vec(),
+    };
+    Some(alt_prefixed(&bytes, mods))
+}
+
+/// Control code sent for Ctrl and a character, following xterm
+fn control_code(c: char) -> Option<u8> {
+    let code = match c {
+        'a'..='z' | 'A'..='Z' => c.to_ascii_uppercase() as u8 - b'@',
+        '@' | ' ' | '2' => 0x00,
+        '[' | '3' => 0x1b,
+        '\\' | '4' => 0x1c,
+        ']' | '5' => 0x1d,
+        '^' | '~' | '6' => 0x1e,
+        '_' | '/' | '7' => 0x1f,
+        '?' | '8' => 0x7f,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Alt is sent as an `ESC` prefix
+fn alt_prefixed(bytes: &[u8], mods: ModifiersState) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 1);
+    if mods.alt_key() {
+        out.push(0x1b);
+    }
+    out.extend_from_slice(bytes);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: Key, mods: ModifiersState, modes: KeyboardModes) -> Option<Vec<u8>> {
+        encode(&key, KeyLocation::Standard, None, mods, modes)
+    }
+
+    fn named(key: NamedKey, mods: ModifiersState, modes: KeyboardModes) -> Vec<u8> {
+        press(Key::Named(key), mods, modes).unwrap()
+    }
+
+    fn text(c: &str, mods: ModifiersState) -> Vec<u8> {
+        press(Key::Character(c.into()), mods, KeyboardModes::default()).unwrap()
+    }
+
+    const APP_CURSOR: KeyboardModes = KeyboardModes {
+        app_cursor: true,
+        app_keypad: false,
+        new_line: false,
+    };
+
+    #[test]
+    fn cursor_keys_follow_decckm() {
+        let none = ModifiersState::empty();
+        assert_eq!(
+            named(NamedKey::ArrowUp, none, KeyboardModes::default()),
+            b"\x1b[A"
+        );
+        assert_eq!(
+            named(NamedKey::ArrowLeft, none, KeyboardModes::default()),
+            b"\x1b[D"
+        );
+        assert_eq!(
+            named(NamedKey::Home, none, KeyboardModes::default()),
+            b"\x1b[H"
+        );
+        assert_eq!(named(NamedKey::ArrowUp, none, APP_CURSOR), b"\x1bOA");
+        assert_eq!(named(NamedKey::End, none, APP_CURSOR), b"\x1bOF");
+    }
+
+    #[test]
+    fn modified_cursor_keys_ignore_decckm() {
+        let shift = ModifiersState::SHIFT;
+        let ctrl_alt = ModifiersState::CONTROL | ModifiersState::ALT;
+        for modes in [KeyboardModes::default(), APP_CURSOR] {
+            assert_eq!(named(NamedKey::ArrowRight, shift, modes), b"\x1b[1;2C");
+            assert_eq!(named(NamedKey::ArrowDown, ctrl_alt, modes), b"\x1b[1;7B");
+        }
+        assert_eq!(
+            named(
+                NamedKey::Delete,
+                ModifiersState::CONTROL,
+                KeyboardModes::default()
+            ),
+            b"\x1b[3;5~"
+        );
+        assert_eq!(
+            named(
+                NamedKey::F1,
+                ModifiersState::SHIFT,
+                KeyboardModes::default()
+            ),
+            b"\x1b[1;2P"
+        );
+    }
+
+    #[test]
+    fn keypad_in_application_mode() {
+        let keypad = KeyboardModes {
+            app_keypad: true,
+            ..Default::default()
+        };
+        let numpad = |key: Key, modes| {
+            encode(
+                &key,
+                KeyLocation::Numpad,
+                None,
+                ModifiersState::empty(),
+                modes,
+            )
+            .unwrap()
+        };
+        assert_eq!(numpad(Key::Character("5".into()), keypad), b"\x1bOu");
+        assert_eq!(numpad(Key::Character("+".into()), keypad), b"\x1bOk");
+        assert_eq!(numpad(Key::Named(NamedKey::Enter), keypad), b"\x1bOM");
+        // Without DECKPAM the keypad sends its characters
+        assert_eq!(
+            numpad(Key::Character("5".into()), KeyboardModes::default()),
+            b"5"
+        );
+        assert_eq!(
+            numpad(Key::Named(NamedKey::Enter), KeyboardModes::default()),
+            b"\r"
+        );
+        // Only the keypad is affected
+        assert_eq!(
+            press(Key::Character("5".into()), ModifiersState::empty(), keypad).unwrap(),
+            b"5"
+        );
+    }
+
+    #[test]
+    fn enter_follows_lnm() {
+        let none = ModifiersState::empty();
+        let new_line = KeyboardModes {
+            new_line: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            named(NamedKey::Enter, none, KeyboardModes::default()),
+            b"\r"
+        );
+        assert_eq!(named(NamedKey::Enter, none, new_line), b"\r\n");
+        assert_eq!(
+            named(NamedKey::Enter, ModifiersState::ALT, new_line),
+            b"\x1b\r\n"
+        );
+    }
+
+    #[test]
+    fn ctrl_sends_control_codes() {
+        let ctrl = ModifiersState::CONTROL;
+        assert_eq!(text("c", ctrl), [0x03]);
+        assert_eq!(text("C", ctrl), [0x03]);
+        assert_eq!(text("[", ctrl), [0x1b]);
+        assert_eq!(text("@", ctrl), [0x00]);
+        assert_eq!(text("?", ctrl), [0x7f]);
+        // Characters without a control code are sent as they are
+        assert_eq!(text("1", ctrl), b"1");
+        assert_eq!(
+            named(NamedKey::Space, ctrl, KeyboardModes::default()),
+            [0x00]
+        );
+        assert_eq!(
+            named(NamedKey::Backspace, ctrl, KeyboardModes::default()),
+            [0x08]
+        );
+    }
+
+    #[test]
+    fn alt_prefixes_escape() {
+        let alt = ModifiersState::ALT;
+        assert_eq!(text("x", ModifiersState::empty()), b"x");
+        assert_eq!(text("x", alt), b"\x1bx");
+        assert_eq!(text("é", alt), "\x1bé".as_bytes());
+        assert_eq!(text("c", alt | ModifiersState::CONTROL), [0x1b, 0x03]);
+        assert_eq!(
+            named(NamedKey::Backspace, alt, KeyboardModes::default()),
+            b"\x1b\x7f"
+        );
+    }
+
+    #[test]
+    fn keys_without_bytes() {
+        assert_eq!(
+            press(
+                Key::Named(NamedKey::Shift),
+                ModifiersState::empty(),
+                KeyboardModes::default()
+            ),
+            None
+        );
+        assert_eq!(text_event(None), None);
+        assert_eq!(text_event(Some("ü")), Some("ü".as_bytes().to_vec()));
+    }
+
+    /// A key without a logical character, like a dead key, that still produced text
+    fn text_event(text: Option<&str>) -> Option<Vec<u8>> {
+        let key = Key::Dead(None);
+        encode(
+            &key,
+            KeyLocation::Standard,
+            text,
+            ModifiersState::empty(),
+            KeyboardModes::default(),
+        )
+    }
+}
@@ -1,48 +1,129 @@
+use self::backend::{BackendKind, RenderBackend, SoftwareState};
+use self::cell::{Attribute, Cell};
 use self::display::Display;
-use self::renderer::Renderer;
-use self::text::GlyphVertex;
+use self::modes::{AnsiMode, Modes, PrivateMode};
+use self::palette::Palette;
+use self::pty::Pty;
+use self::reader::PtyReader;
+use self::renderer::{RenderCursor, RenderFrame, Renderer};
+use self::text::{
+    BackgroundInstance, CellInstance, QuadVertex, RectInstance, ScreenUniform, ATLAS_SIZE,
+};
+use image::RgbaImage;
 use rusttype::Scale;
-use std::io::{ErrorKind, Read};
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{ErrorKind, Write};
 use std::ops::Range;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use term::data::cursor::Cursor;
 use term::data::grids::Grid;
-use term::data::{Attribute, Cell, Color, Column, Line, RGBA};
-use term::pty::PTY;
+use term::data::{Color, Column, Line, PositionedCell, RGBA};
+use term::ViewPort;
 use tokio::runtime::Runtime;
 use vte::VTEParser;
 use wgpu::util::{BufferInitDescriptor, DeviceExt, RenderEncoder};
 use wgpu::{include_wgsl, Origin2d, Origin3d, TextureAspect};
 use winit::application::ApplicationHandler;
 use winit::dpi::PhysicalSize;
-use winit::window::Window;
+use winit::event::{ElementState, KeyEvent, MouseScrollDelta};
+use winit::event_loop::{ControlFlow, EventLoopProxy};
+use winit::keyboard::{Key, ModifiersState, NamedKey};
+use winit::window::{UserAttentionType, Window};
+pub mod backend;
+pub mod cell;
 pub mod display;
+pub mod input;
+pub mod modes;
+pub mod palette;
+pub mod pty;
+pub mod reader;
 pub mod renderer;
 pub mod text;
 
 pub struct App<'config> {
     colorscheme: &'config [RGBA; 16],
     scale: Scale,
-    display: Option<Display<'config>>,
-    pty: PTY,
+    display: Option<Display>,
+    pty: Pty,
+    reader: PtyReader,
+    /// Passed to every [`PtyReader`], also the respawned ones
+    wake: Arc<dyn Fn() + Send + Sync>,
     parser: VTEParser,
+    exit_action: ExitAction,
+    shell_exited: bool,
 
-    renderer: Option<Renderer<'config>>,
-    state: Option<DisplayState>,
+    renderer: Option<Renderer>,
+    backend: BackendKind,
+    state: Option<Box<dyn RenderBackend>>,
+
+    /// Lines of history kept by the [`Terminal`]
+    scrollback_lines: usize,
+
+    focused: bool,
+    modifiers: ModifiersState,
+    /// Whether a blinking cursor is in its visible phase
+    blink_on: bool,
+    next_blink: Instant,
+}
+
+/// Events sent to the event loop from other threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppEvent {
+    /// The [`PtyReader`] has output waiting
+    PtyOutput,
 }
 
+/// What happens when the shell exits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Close the window
+    #[default]
+    Close,
+    /// Keep the window open with a banner showing the exit code
+    Hold,
+    /// Start a new shell in the same window
+    Respawn,
+}
+
+/// Window title when the shell didn't set one
+const DEFAULT_TITLE: &str = env!("CARGO_PKG_NAME");
+
+/// Time a blinking cursor stays on or off
+const CURSOR_BLINK_INTERVAL: Duration = Duration::from_millis(530);
+
+/// Lines of history kept when no other limit is set
+pub const DEFAULT_SCROLLBACK_LINES: usize = 10_000;
+
+/// Lines scrolled by one step of a mouse wheel
+const WHEEL_SCROLL_LINES: f32 = 3.0;
+
 pub struct DisplayState {
-    window: Arc<Window>,
-    surface: wgpu::Surface<'static>,
+    /// `None` when rendering headless
+    window: Option<Arc<Window>>,
+    surface: Option<wgpu::Surface<'static>>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     pipe_line: wgpu::RenderPipeline,
     size: PhysicalSize<u32>,
     config: wgpu::SurfaceConfiguration,
-    texture_bind_group_layout: wgpu::BindGroupLayout,
-    texture_sampler: wgpu::Sampler,
-    buffer: wgpu::Buffer,
-    num_vertices: usize,
+    atlas: wgpu::Texture,
+    atlas_bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    quad_buffer: wgpu::Buffer,
+    /// Persistent buffer with one instance per cell, only grows when the grid does
+    instance_buffer: wgpu::Buffer,
+    num_instances: usize,
+    background_pipeline: wgpu::RenderPipeline,
+    /// Background runs, rewritten every frame
+    background_buffer: wgpu::Buffer,
+    num_backgrounds: usize,
+    rect_pipeline: wgpu::RenderPipeline,
+    /// Overlay rectangles like the cursor, rewritten every frame
+    overlay_buffer: wgpu::Buffer,
+    num_overlays: usize,
+    clear_color: wgpu::Color,
 }
 
 impl DisplayState {
@@ -55,32 +136,7 @@ impl DisplayState {
 
         let surface = instance.create_surface(window.clone()).unwrap();
 
-        let rt = Runtime::new().unwrap();
-        let (adapter, (device, queue)) = rt.block_on(async {
-            let adapter = instance
-                .request_adapter(&wgpu::RequestAdapterOptions {
-                    power_preference: wgpu::PowerPreference::HighPerformance,
-                    force_fallback_adapter: false,
-                    compatible_surface: Some(&surface),
-                })
-                .await
-                .unwrap();
-
-            let (device, queue) = adapter
-                .request_device(
-                    &wgpu::DeviceDescriptor {
-                        label: None,
-                        required_features: wgpu::Features::FLOAT32_FILTERABLE,
-                        required_limits: wgpu::Limits::default(),
-                        memory_hints: wgpu::MemoryHints::Performance,
-                    },
-                    None,
-                )
-                .await
-                .unwrap();
-
-            (adapter, (device, queue))
-        });
+        let (adapter, device, queue) = request_device(&instance, Some(&surface));
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
@@ -105,6 +161,44 @@ impl DisplayState {
 
         surface.configure(&device, &config);
 
+        Self::with_device(Some(window), Some(surface), device, queue, size, config)
+    }
+
+    /// Create a display state without a window, frames can only be read back
+    /// with [`DisplayState::render_to_image`]. Falls back to a software adapter
+    /// when there is no usable GPU.
+    pub fn new_headless(size: PhysicalSize<u32>) -> Self {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+
+        let (_, device, queue) = request_device(&instance, None);
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            // The shader outputs the sRGB colors of the cells as they are, an sRGB
+            // target would encode them a second time
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            width: size.width,
+            height: size.height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+
+        Self::with_device(None, None, device, queue, size, config)
+    }
+
+    fn with_device(
+        window: Option<Arc<Window>>,
+        surface: Option<wgpu::Surface<'static>>,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        size: PhysicalSize<u32>,
+        config: wgpu::SurfaceConfiguration,
+    ) -> Self {
         let main_shader = include_wgsl!("./alt_shader.wgsl");
         let vs = device.create_shader_module(main_shader);
 
@@ -141,10 +235,25 @@ impl DisplayState {
                 label: Some("texture bind group layout"),
             });
 
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("uniform bind group layout"),
+            });
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&texture_bind_group_layout],
+                bind_group_layouts: &[&texture_bind_group_layout, &uniform_bind_group_layout],
                 push_constant_ranges: &[],
             });
 
@@ -155,7 +264,7 @@ impl DisplayState {
                 module: &vs,
                 entry_point: Some("vs_main"),
                 compilation_options: Default::default(),
-                buffers: &[GlyphVertex::desc()],
+                buffers: &[QuadVertex::desc(), CellInstance::desc()],
             },
             depth_stencil: None,
             multisample: wgpu::MultisampleState {
@@ -187,13 +296,147 @@ impl DisplayState {
             cache: None,
         });
 
-        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("screen buffer"),
-            size: 100_000_000,
-            usage: wgpu::BufferUsages::VERTEX,
+        let background_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("background render pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs,
+                entry_point: Some("vs_background"),
+                compilation_options: Default::default(),
+                buffers: &[QuadVertex::desc(), BackgroundInstance::desc()],
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &vs,
+                entry_point: Some("fs_background"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                cull_mode: None,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            multiview: None,
+            cache: None,
+        });
+
+        let rect_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("rect render pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs,
+                entry_point: Some("vs_rect"),
+                compilation_options: Default::default(),
+                buffers: &[QuadVertex::desc(), RectInstance::desc()],
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &vs,
+                entry_point: Some("fs_background"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                cull_mode: None,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            multiview: None,
+            cache: None,
+        });
+
+        let atlas = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("glyph atlas"),
+            size: wgpu::Extent3d {
+                width: ATLAS_SIZE,
+                height: ATLAS_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let atlas_view = atlas.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("texture view for atlas"),
+            format: Some(wgpu::TextureFormat::R8Unorm),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            ..Default::default()
+        });
+
+        let atlas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("atlas bind group"),
+            layout: &texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture_sampler),
+                },
+            ],
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("screen uniform buffer"),
+            size: std::mem::size_of::<ScreenUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("uniform bind group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+
+        let quad_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("unit quad buffer"),
+            contents: bytemuck::cast_slice(&QuadVertex::UNIT_QUAD),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let instance_buffer = create_instance_buffer::<CellInstance>(&device, 0);
+        let background_buffer = create_instance_buffer::<BackgroundInstance>(&device, 0);
+        let overlay_buffer = create_instance_buffer::<RectInstance>(&device, 0);
+
         Self {
             window,
             surface,
@@ -202,31 +445,55 @@ impl DisplayState {
             pipe_line,
             size,
             config,
-            buffer,
-            num_vertices: 0,
-            texture_sampler,
-            texture_bind_group_layout,
+            uniform_buffer,
+            uniform_bind_group,
+            quad_buffer,
+            instance_buffer,
+            num_instances: 0,
+            background_pipeline,
+            background_buffer,
+            num_backgrounds: 0,
+            rect_pipeline,
+            overlay_buffer,
+            num_overlays: 0,
+            clear_color: wgpu::Color::WHITE,
+            atlas,
+            atlas_bind_group,
         }
     }
 
-    pub fn rerender_state(&mut self, glyph: usize, buffer: Vec<GlyphVertex>) {
-        self.num_vertices = glyph;
-        self.buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("screen buffer"),
-            contents: bytemuck::cast_slice(&buffer),
-            usage: wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::UNIFORM
-                | wgpu::BufferUsages::VERTEX,
-        });
+    /// Upload the dirty regions of the glyph atlas
+    fn upload_atlas(&self, frame: &RenderFrame) {
+        for update in &frame.atlas_updates {
+            let width = update.rect.width();
+            let height = update.rect.height();
+            self.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.atlas,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: update.rect.min.x,
+                        y: update.rect.min.y,
+                        z: 0,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                &update.data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(width),
+                    rows_per_image: Some(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
     }
 
-    pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        println!("rendering ");
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
+    fn draw(&self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -234,57 +501,13 @@ impl DisplayState {
             });
 
         {
-            let texture_size = wgpu::Extent3d {
-                width: self.size.width,
-                height: self.size.height,
-                depth_or_array_layers: 1,
-            };
-
-            let texture = self.device.create_texture(&wgpu::TextureDescriptor {
-                label: Some("texture"),
-                size: texture_size,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba32Float,
-                usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-                view_formats: &[],
-            });
-
-            let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-                label: Some("texture view for atlas"),
-                format: Some(wgpu::TextureFormat::Rgba32Float),
-                dimension: Some(wgpu::TextureViewDimension::D2),
-                ..Default::default()
-            });
-
-            let atlas_linear = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("al bind group"),
-                layout: &self.texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&texture_view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&self.texture_sampler),
-                    },
-                ],
-            });
-
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 1.0,
-                            g: 1.0,
-                            b: 1.0,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(self.clear_color),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -293,118 +516,644 @@ impl DisplayState {
                 timestamp_writes: None,
             });
 
+            render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.quad_buffer.slice(..));
+
+            // Backgrounds first so glyphs are blended on top of them
+            render_pass.set_pipeline(&self.background_pipeline);
+            render_pass.set_vertex_buffer(1, self.background_buffer.slice(..));
+            render_pass.draw(
+                0..QuadVertex::UNIT_QUAD.len() as u32,
+                0..self.num_backgrounds as u32,
+            );
+
             render_pass.set_pipeline(&self.pipe_line);
-            render_pass.set_bind_group(0, &atlas_linear, &[]);
-            render_pass.set_vertex_buffer(0, self.buffer.slice(..));
-            render_pass.draw(0..self.num_vertices as u32, 0..1);
+            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            render_pass.draw(
+                0..QuadVertex::UNIT_QUAD.len() as u32,
+                0..self.num_instances as u32,
+            );
+
+            render_pass.set_pipeline(&self.rect_pipeline);
+            render_pass.set_vertex_buffer(1, self.overlay_buffer.slice(..));
+            render_pass.draw(
+                0..QuadVertex::UNIT_QUAD.len() as u32,
+                0..self.num_overlays as u32,
+            );
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+impl RenderBackend for DisplayState {
+    fn rerender_state(&mut self, frame: RenderFrame) {
+        self.upload_atlas(&frame);
+
+        let uniform = ScreenUniform {
+            screen_size: [self.config.width as f32, self.config.height as f32],
+            cell_size: [frame.cell_size[0] as f32, frame.cell_size[1] as f32],
+            atlas_size: [ATLAS_SIZE as f32, ATLAS_SIZE as f32],
+            _padding: [0.0; 2],
+        };
+        self.queue
+            .write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+
+        let size = (frame.cells * std::mem::size_of::<CellInstance>()) as wgpu::BufferAddress;
+        if size > self.instance_buffer.size() {
+            // The grid only grows together with a full redraw, so nothing is lost
+            self.instance_buffer =
+                create_instance_buffer::<CellInstance>(&self.device, frame.cells);
+        }
+        for damage in &frame.damage {
+            self.queue.write_buffer(
+                &self.instance_buffer,
+                (damage.offset * std::mem::size_of::<CellInstance>()) as wgpu::BufferAddress,
+                bytemuck::cast_slice(&damage.instances),
+            );
+        }
+        self.num_instances = frame.cells;
+
+        let size = std::mem::size_of_val(frame.backgrounds.as_slice()) as wgpu::BufferAddress;
+        if size > self.background_buffer.size() {
+            self.background_buffer =
+                create_instance_buffer::<BackgroundInstance>(&self.device, frame.backgrounds.len());
+        }
+        self.queue.write_buffer(
+            &self.background_buffer,
+            0,
+            bytemuck::cast_slice(&frame.backgrounds),
+        );
+        self.num_backgrounds = frame.backgrounds.len();
+
+        let size = std::mem::size_of_val(frame.overlays.as_slice()) as wgpu::BufferAddress;
+        if size > self.overlay_buffer.size() {
+            self.overlay_buffer =
+                create_instance_buffer::<RectInstance>(&self.device, frame.overlays.len());
+        }
+        self.queue.write_buffer(
+            &self.overlay_buffer,
+            0,
+            bytemuck::cast_slice(&frame.overlays),
+        );
+        self.num_overlays = frame.overlays.len();
+
+        let [r, g, b, a] = frame.background.map(|c| c as f64 / 255.0);
+        self.clear_color = wgpu::Color { r, g, b, a };
+    }
+
+    fn window(&self) -> Option<&Arc<Window>> {
+        self.window.as_ref()
+    }
+
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        let Some(surface) = self.surface.as_ref() else {
+            return Ok(());
+        };
+        let output = surface.get_current_texture()?;
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        self.draw(&view);
         output.present();
 
         Ok(())
     }
 
-    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+    /// Render the current frame into an offscreen texture and read it back
+    fn render_to_image(&self) -> RgbaImage {
+        let width = self.config.width;
+        let height = self.config.height;
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen frame"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        self.draw(&texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        // Rows copied into a buffer have to be aligned
+        let unpadded_row = width * 4;
+        let padded_row = unpadded_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let output = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen readback buffer"),
+            size: (padded_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("readback encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &output,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            size,
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = output.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::Maintain::Wait);
+
+        let mut pixels = Vec::with_capacity((unpadded_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_row as usize]);
+            }
+        }
+        output.unmap();
+
+        if matches!(
+            self.config.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            pixels
+                .chunks_exact_mut(4)
+                .for_each(|pixel| pixel.swap(0, 2));
+        }
+
+        RgbaImage::from_raw(width, height, pixels).unwrap()
+    }
+
+    fn resize(&mut self, new_size: PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            if let Some(surface) = self.surface.as_ref() {
+                surface.configure(&self.device, &self.config);
+            }
         }
     }
+
+    fn size(&self) -> PhysicalSize<u32> {
+        self.size
+    }
+}
+
+/// Vertex buffer that can hold `count` instances of `T`
+fn create_instance_buffer<T>(device: &wgpu::Device, count: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(std::any::type_name::<T>()),
+        size: (count.max(1) * std::mem::size_of::<T>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Request a device, trying the software fallback adapter if no hardware adapter is found
+fn request_device(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'static>>,
+) -> (wgpu::Adapter, wgpu::Device, wgpu::Queue) {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut adapter = None;
+        for force_fallback_adapter in [false, true] {
+            adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    force_fallback_adapter,
+                    compatible_surface: surface,
+                })
+                .await;
+            if adapter.is_some() {
+                break;
+            }
+        }
+        let adapter = adapter.unwrap();
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    required_features: wgpu::Features::empty(),
+                    required_limits: wgpu::Limits::downlevel_defaults()
+                        .using_resolution(adapter.limits()),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        (adapter, device, queue)
+    })
 }
 
 impl<'config> App<'config> {
-    pub fn new(colorscheme: &'config [RGBA; 16], scale: Scale, pty: PTY) -> Self {
+    pub fn new(
+        colorscheme: &'config [RGBA; 16],
+        scale: Scale,
+        pty: Pty,
+        backend: BackendKind,
+        proxy: EventLoopProxy<AppEvent>,
+    ) -> Self {
+        let wake = Arc::new(move || {
+            // Fails only when the event loop is already gone
+            let _ = proxy.send_event(AppEvent::PtyOutput);
+        });
+        Self::with_wakeup(colorscheme, scale, pty, backend, wake)
+    }
+
+    /// * `wake`: Called from the reader thread when the PTY has output
+    fn with_wakeup(
+        colorscheme: &'config [RGBA; 16],
+        scale: Scale,
+        mut pty: Pty,
+        backend: BackendKind,
+        wake: Arc<dyn Fn() + Send + Sync>,
+    ) -> Self {
+        let io = pty.io().try_clone().unwrap();
+        let reader = PtyReader::spawn(io, pty.child(), Arc::clone(&wake)).unwrap();
         Self {
             colorscheme,
             display: None,
             renderer: None,
+            backend,
             scale,
             state: None,
             pty,
+            reader,
+            wake,
             parser: VTEParser::new(),
+            exit_action: ExitAction::default(),
+            shell_exited: false,
+            scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+            focused: true,
+            modifiers: ModifiersState::empty(),
+            blink_on: true,
+            next_blink: Instant::now() + CURSOR_BLINK_INTERVAL,
         }
     }
 
+    /// Parse the output the reader has collected and prepare a frame
     pub fn update(&mut self) {
-        let mut curr = 0;
-
-        let reader = self.pty.io();
-
-        let mut buff = vec![0; 2048];
-        loop {
-            match reader.read(&mut buff[curr..]) {
-                Ok(n) => {
-                    if n == 0 {
-                        return;
-                    } else {
-                        curr += n;
-                        if curr > 100 {
-                            break;
-                        }
-                    }
-                }
-                Err(_e) => break,
+        // Output stays queued until there is a display to parse it into
+        let Some(display) = self.display.as_mut() else {
+            return;
+        };
+
+        let batch = self.reader.read_batch();
+        self.parser.parse(&batch.output, display);
+        // Text after the last control function, like a prompt, is drawn right away
+        display.write_pending();
+        let responses = display.take_responses();
+        if !responses.is_empty() && !self.shell_exited {
+            if let Err(e) = self.pty.io().write_all(&responses) {
+                tracing::warn!("failed to answer the shell: {e}");
             }
         }
+        if display.take_title_changed() {
+            let title = display.title().unwrap_or(DEFAULT_TITLE);
+            if let Some(window) = self.state.as_ref().and_then(|state| state.window()) {
+                window.set_title(title);
+            }
+        }
+        if display.take_palette_changed() {
+            if let Some(renderer) = self.renderer.as_mut() {
+                renderer.set_palette(display.term.palette.clone());
+            }
+        }
+        if display.take_bell() {
+            self.ring_bell();
+        }
+        if let Some(code) = batch.exit {
+            self.child_exited(code);
+        }
 
-        self.parser
-            .parse(&buff[..curr], self.display.as_mut().unwrap());
-
-        let render = self.renderer.as_ref().unwrap();
-        let buffer = render.prepare_render(self.display.as_ref().unwrap().grid_iter(Line(0)));
-        self.state
-            .as_mut()
-            .unwrap()
-            .rerender_state(buffer.len(), buffer);
+        self.redraw();
     }
 
-    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
-        self.state.as_mut().unwrap().resize(new_size);
+    pub fn set_exit_action(&mut self, action: ExitAction) {
+        self.exit_action = action;
     }
-}
 
-impl ApplicationHandler for App<'_> {
-    fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
-        if self.display.is_none() {
-            let window = Arc::new(
-                event_loop
-                    .create_window(Window::default_attributes())
-                    .unwrap(),
-            );
-            let size = window.inner_size();
-            self.state = Some(DisplayState::new(Arc::clone(&window)));
+    /// Set how many lines scrolled off the top are kept, 0 disables the history
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.scrollback_lines = lines;
+        if let Some(display) = self.display.as_mut() {
+            display.term.set_scrollback_limit(lines);
+        }
+    }
 
-            self.display = Some(Display::new(
-                size.width,
-                size.height,
-                self.scale,
-                self.colorscheme,
-            ));
+    /// Scroll the view into the history, negative `lines` scroll back towards the shell
+    fn scroll_view(&mut self, lines: isize) {
+        let Some(display) = self.display.as_mut() else {
+            return;
+        };
+        // The alternate screen has no history to scroll into
+        if display.term.alt_screen() {
+            return;
+        }
+        if display.term.scroll_display(lines) {
+            self.redraw();
+        }
+    }
 
-            self.renderer = Some(Renderer::new(
-                size.width,
-                size.height,
-                self.scale,
-                self.colorscheme,
-            ));
+    /// Lines a mouse wheel event scrolls by, positive when scrolling up
+    fn wheel_lines(&self, delta: MouseScrollDelta) -> isize {
+        match delta {
+            MouseScrollDelta::LineDelta(_, y) => (y * WHEEL_SCROLL_LINES).round() as isize,
+            MouseScrollDelta::PixelDelta(position) => {
+                (position.y / self.scale.y.round() as f64).round() as isize
+            }
         }
     }
 
-    fn window_event(
-        &mut self,
-        event_loop: &winit::event_loop::ActiveEventLoop,
-        window_id: winit::window::WindowId,
-        event: winit::event::WindowEvent,
-    ) {
-        if window_id != self.state.as_ref().unwrap().window.id() {
-            std::process::exit(1)
+    /// Show the exit code of the shell and start a new one if asked to.
+    /// Closing the window is left to the event loop.
+    fn child_exited(&mut self, code: Option<i32>) {
+        self.shell_exited = true;
+        if self.exit_action == ExitAction::Close {
+            return;
         }
 
-        self.state.as_mut().unwrap().window.request_redraw();
-        self.update();
+        let banner = match code {
+            Some(code) => format!("\r\n[process exited with code {code}]\r\n"),
+            None => "\r\n[process exited]\r\n".to_string(),
+        };
+        if let Some(display) = self.display.as_mut() {
+            self.parser.parse(banner.as_bytes(), display);
+        }
+
+        if self.exit_action == ExitAction::Respawn {
+            self.respawn();
+        }
+    }
+
+    /// Replace the exited shell with a new one
+    fn respawn(&mut self) {
+        // The new shell starts where the old one was, if that directory is still there
+        let directory = self
+            .display
+            .as_ref()
+            .and_then(|display| display.working_directory())
+            .filter(|directory| directory.is_dir());
+        let mut pty = match Pty::spawn(&self.viewport(), directory) {
+            Ok(pty) => pty,
+            Err(e) => {
+                tracing::error!("failed to respawn the shell: {e}");
+                return;
+            }
+        };
+        let io = pty.io().try_clone().unwrap();
+        match PtyReader::spawn(io, pty.child(), Arc::clone(&self.wake)) {
+            Ok(reader) => {
+                self.pty = pty;
+                self.reader = reader;
+                self.shell_exited = false;
+            }
+            Err(e) => tracing::error!("failed to read the respawned shell: {e}"),
+        }
+    }
+
+    /// Size of the grid and its cells, computed the same way as the [`Renderer`] does
+    fn viewport(&self) -> ViewPort {
+        let size = self
+            .state
+            .as_ref()
+            .map_or(PhysicalSize::new(0, 0), |state| state.size());
+        let cell_height = self.scale.y.round() as u32;
+        let cell_width = (self.scale.x / 2.0).round() as u32;
+        ViewPort {
+            x: (size.height / cell_height) as u16,
+            y: (size.width / cell_width) as u16,
+            cx: cell_width as u16,
+            cy: cell_height as u16,
+        }
+    }
+
+    /// Prepare a frame with the damaged lines and the cursor, if anything changed
+    fn redraw(&mut self) {
+        let cursor = self.render_cursor();
+        let (Some(display), Some(render)) = (self.display.as_mut(), self.renderer.as_mut()) else {
+            return;
+        };
+
+        let damage = display.term.damaged_lines();
+        if !render.set_cursor(cursor) && damage.is_empty() {
+            return;
+        }
+
+        render.set_line_offset(display.term.view_start());
+        let frame = render.prepare_render(display.term.view_iter(&damage));
+        display.term.clear_damage(&damage);
+
+        let state = self.state.as_mut().unwrap();
+        state.rerender_state(frame);
+        if let Some(window) = state.window() {
+            window.request_redraw();
+        }
+    }
+
+    /// The cursor as it should be drawn right now
+    fn render_cursor(&self) -> Option<RenderCursor> {
+        let display = self.display.as_ref()?;
+        let (cursor, style) = display.cursor()?;
+        // The cursor only blinks while the window is focused
+        if style.blinking && self.focused && !self.blink_on {
+            return None;
+        }
+        Some(RenderCursor {
+            // The renderer counts lines from the start of the history
+            line: display.term.history_len() + cursor.line.0,
+            col: cursor.column.0,
+            shape: style.shape,
+            hollow: !self.focused,
+        })
+    }
+
+    /// Encode a key press and send it to the shell
+    fn send_key(&mut self, event: &KeyEvent) {
+        let Some(display) = self.display.as_mut() else {
+            return;
+        };
+
+        // Shift+PageUp and Shift+PageDown scroll the view instead of going to the shell,
+        // full screen programs on the alternate screen get them
+        if self.modifiers.shift_key() && !display.term.alt_screen() {
+            let page = display.term.lines().saturating_sub(1).max(1) as isize;
+            match event.logical_key {
+                Key::Named(NamedKey::PageUp) => return self.scroll_view(page),
+                Key::Named(NamedKey::PageDown) => return self.scroll_view(-page),
+                _ => {}
+            }
+        }
+
+        if self.shell_exited {
+            return;
+        }
+        let Some(bytes) = input::encode_key(event, self.modifiers, display.keyboard_modes()) else {
+            return;
+        };
+        if let Err(e) = self.pty.io().write_all(&bytes) {
+            tracing::warn!("failed to write key to pty: {e}");
+        }
+        // Typing jumps back to the shell
+        if display.term.reset_display() {
+            self.redraw();
+        }
+        self.reset_blink();
+    }
+
+    /// Tell the shell about focus changes when it asked for them
+    fn report_focus(&mut self) {
+        let reporting = self
+            .display
+            .as_ref()
+            .is_some_and(|display| display.term.modes.private(PrivateMode::FocusEvents));
+        if !reporting || self.shell_exited {
+            return;
+        }
+        let report: &[u8] = if self.focused { b"\x1b[I" } else { b"\x1b[O" };
+        if let Err(e) = self.pty.io().write_all(report) {
+            tracing::warn!("failed to report focus: {e}");
+        }
+    }
+
+    /// Ask for attention when a BEL arrives while the window is in the background
+    fn ring_bell(&self) {
+        if self.focused {
+            return;
+        }
+        if let Some(window) = self.state.as_ref().and_then(|state| state.window()) {
+            window.request_user_attention(Some(UserAttentionType::Informational));
+        }
+    }
+
+    /// Restart the blink cycle with the cursor visible
+    fn reset_blink(&mut self) {
+        self.blink_on = true;
+        self.next_blink = Instant::now() + CURSOR_BLINK_INTERVAL;
+    }
+
+    /// Create an app that renders without a window, see [`App::render_to_image`]
+    pub fn headless(
+        colorscheme: &'config [RGBA; 16],
+        scale: Scale,
+        pty: Pty,
+        backend: BackendKind,
+        size: PhysicalSize<u32>,
+    ) -> Self {
+        let mut app = Self::with_wakeup(colorscheme, scale, pty, backend, Arc::new(|| {}));
+        app.state = Some(match backend {
+            BackendKind::Gpu => Box::new(DisplayState::new_headless(size)),
+            BackendKind::Cpu => Box::new(SoftwareState::new_headless(size)),
+        });
+        let palette = Palette::new(colorscheme);
+        let mut display = Display::new(size.width, size.height, scale, palette.clone());
+        display.term.set_scrollback_limit(app.scrollback_lines);
+        app.display = Some(display);
+        app.renderer = Some(Renderer::new(size.width, size.height, scale, palette));
+        app
+    }
+
+    /// Read back the last rendered frame
+    pub fn render_to_image(&self) -> RgbaImage {
+        self.state.as_ref().unwrap().render_to_image()
+    }
+
+    /// Resize the surface, the grid and the renderer together and tell the shell
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        self.state.as_mut().unwrap().resize(new_size);
+
+        // Nothing fits when minimized or smaller than a cell
+        let viewport = self.viewport();
+        if viewport.x == 0 || viewport.y == 0 {
+            return;
+        }
+        if let Some(display) = self.display.as_mut() {
+            display.resize(new_size.width, new_size.height, self.scale);
+        }
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.resize(new_size.width, new_size.height);
+        }
+        if let Err(e) = self.pty.resize(&viewport) {
+            tracing::warn!("failed to resize the pty: {e}");
+        }
+        self.redraw();
+    }
+}
+
+impl ApplicationHandler<AppEvent> for App<'_> {
+    fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        if self.display.is_none() {
+            let window = Arc::new(
+                event_loop
+                    .create_window(Window::default_attributes().with_title(DEFAULT_TITLE))
+                    .unwrap(),
+            );
+            let size = window.inner_size();
+            self.state = Some(match self.backend {
+                BackendKind::Gpu => Box::new(DisplayState::new(Arc::clone(&window))),
+                BackendKind::Cpu => Box::new(SoftwareState::new(Arc::clone(&window))),
+            });
+
+            let palette = Palette::new(self.colorscheme);
+            let mut display = Display::new(size.width, size.height, self.scale, palette.clone());
+            display.term.set_scrollback_limit(self.scrollback_lines);
+            self.display = Some(display);
+
+            self.renderer = Some(Renderer::new(size.width, size.height, self.scale, palette));
+
+            // Take whatever the shell printed before the window existed
+            self.update();
+        }
+    }
+
+    fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: AppEvent) {
+        match event {
+            AppEvent::PtyOutput => {
+                self.update();
+                if self.shell_exited && self.exit_action == ExitAction::Close {
+                    event_loop.exit();
+                }
+            }
+        }
+    }
+
+    fn window_event(
+        &mut self,
+        event_loop: &winit::event_loop::ActiveEventLoop,
+        window_id: winit::window::WindowId,
+        event: winit::event::WindowEvent,
+    ) {
+        let Some(window) = self.state.as_ref().unwrap().window() else {
+            return;
+        };
+        if window_id != window.id() {
+            std::process::exit(1)
+        }
 
         let state = self.state.as_mut().unwrap();
         match event {
@@ -415,6 +1164,23 @@ impl ApplicationHandler for App<'_> {
                 event_loop.exit();
             }
             winit::event::WindowEvent::Resized(new_size) => self.resize(new_size),
+            winit::event::WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state()
+            }
+            winit::event::WindowEvent::KeyboardInput { event, .. }
+                if event.state == ElementState::Pressed =>
+            {
+                self.send_key(&event)
+            }
+            winit::event::WindowEvent::MouseWheel { delta, .. } => {
+                self.scroll_view(self.wheel_lines(delta))
+            }
+            winit::event::WindowEvent::Focused(focused) => {
+                self.focused = focused;
+                self.report_focus();
+                self.reset_blink();
+                self.redraw();
+            }
             winit::event::WindowEvent::RedrawRequested => match state.render() {
                 Ok(_) => {
                     // println!("rendered");
@@ -424,7 +1190,8 @@ impl ApplicationHandler for App<'_> {
                     match e {
                         wgpu::SurfaceError::Timeout => {}
                         wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost => {
-                            state.resize(state.size)
+                            let size = state.size();
+                            state.resize(size)
                         }
                         wgpu::SurfaceError::OutOfMemory => {
                             event_loop.exit();
@@ -435,139 +1202,879 @@ impl ApplicationHandler for App<'_> {
             _ => {}
         }
     }
+
+    fn about_to_wait(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        let blinking = self.focused
+            && self
+                .display
+                .as_ref()
+                .and_then(|display| display.cursor())
+                .is_some_and(|(_, style)| style.blinking);
+        if !blinking {
+            self.blink_on = true;
+            event_loop.set_control_flow(ControlFlow::Wait);
+            return;
+        }
+
+        if Instant::now() >= self.next_blink {
+            self.blink_on = !self.blink_on;
+            self.next_blink = Instant::now() + CURSOR_BLINK_INTERVAL;
+            self.redraw();
+        }
+        event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_blink));
+    }
 }
 
 #[derive(Debug)]
-pub struct Terminal<'config> {
-    scheme: &'config [RGBA; 16],
+pub struct Terminal {
+    /// Colors cells are drawn with, programs can change them with OSC sequences
+    pub palette: Palette,
 
-    fg: Color,
-    bg: Color,
+    /// `None` is the default color, drawn in the dynamic colors of the palette
+    fg: Option<Color>,
+    bg: Option<Color>,
     attr: Attribute,
+    /// The colors were set with SGR 30-37 and 40-47, which light mode stores like 90-97
+    fg_base: bool,
+    bg_base: bool,
 
     dark_mode: bool,
     pub data: Grid<Cell>,
     pub write_stack: Vec<Cell>,
+    /// Lines of `data` that continue on the next line because the text wrapped
+    wrapped: Vec<bool>,
+    /// The screen that is not shown, swapped with `data` when switching screens
+    inactive_data: Grid<Cell>,
+    inactive_wrapped: Vec<bool>,
+    /// Whether `data` is the alternate screen
+    alt_screen: bool,
+
+    /// Lines that changed without going through a cell's `dirty` flag
+    damaged_lines: BTreeSet<usize>,
+    /// Every line has to be redrawn
+    full_damage: bool,
+
+    /// Top scroll margin
+    scroll_top: usize,
+    /// Bottom scroll margin, inclusive
+    scroll_bottom: usize,
+
+    /// Columns with a tab stop
+    tab_stops: Vec<bool>,
+    pub modes: Modes,
+    /// The last column was written, the next cell goes to the next line
+    wrap_pending: bool,
+
+    /// Lines scrolled off the top of the screen, oldest first
+    history: VecDeque<HistoryLine>,
+    /// Most lines kept in `history`
+    scrollback_limit: usize,
+    /// How many lines the view is scrolled back into `history`
+    display_offset: usize,
+}
+
+/// Colors and attributes given to new cells
+#[derive(Debug, Clone)]
+pub struct GraphicState {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    attr: Attribute,
+    fg_base: bool,
+    bg_base: bool,
+}
+
+/// A line scrolled off the top of the screen
+#[derive(Debug)]
+struct HistoryLine {
+    cells: Vec<Cell>,
+    /// Continues on the next line
+    wrapped: bool,
+}
+
+/// A cell of [`Terminal::view_iter`]
+pub struct ViewCell<'a> {
+    line: Line,
+    column: Column,
+    cell: &'a Cell,
 }
 
-impl<'config> Terminal<'config> {
-    pub fn new(max_row: usize, max_col: usize, colorscheme: &'config [RGBA; 16]) -> Self {
+impl<'a> PositionedCell<&'a Cell> for ViewCell<'a> {
+    fn position(&self) -> (Line, Column) {
+        (self.line, self.column)
+    }
+
+    fn cell(&self) -> &'a Cell {
+        self.cell
+    }
+}
+
+impl Terminal {
+    pub fn new(max_row: usize, max_col: usize, palette: Palette) -> Self {
         Self {
-            scheme: colorscheme,
-            fg: Color::IndexBase(7),
-            bg: Color::IndexBase(0),
+            palette,
+            fg: None,
+            bg: None,
             attr: Attribute::default(),
+            fg_base: false,
+            bg_base: false,
             dark_mode: false,
             data: Grid::new(max_col, max_row),
+            wrapped: vec![false; max_row],
+            inactive_data: Grid::new(max_col, max_row),
+            inactive_wrapped: vec![false; max_row],
+            alt_screen: false,
             write_stack: Vec::with_capacity(25),
+            damaged_lines: BTreeSet::new(),
+            full_damage: true,
+            scroll_top: 0,
+            scroll_bottom: max_row.saturating_sub(1),
+            tab_stops: default_tab_stops(max_col),
+            modes: Modes::default(),
+            wrap_pending: false,
+            history: VecDeque::new(),
+            scrollback_limit: DEFAULT_SCROLLBACK_LINES,
+            display_offset: 0,
+        }
+    }
+
+    /// Resize both screens. The primary screen is reflowed to the new width together with
+    /// the history, and the cursor stays on the same cell of the text.
+    ///
+    /// * `saved`: Cursor saved when the alternate screen was entered, the hidden primary
+    ///   screen is reflowed around it
+    pub fn resize(
+        &mut self,
+        max_row: usize,
+        max_col: usize,
+        cursor: &mut Cursor,
+        saved: Option<&mut Cursor>,
+    ) {
+        if max_row == 0 || max_col == 0 {
+            return;
+        }
+        self.update(cursor);
+
+        if self.alt_screen {
+            std::mem::swap(&mut self.data, &mut self.inactive_data);
+            std::mem::swap(&mut self.wrapped, &mut self.inactive_wrapped);
+            match saved {
+                Some(saved) => self.reflow(max_row, max_col, saved),
+                // Without a saved cursor the primary screen is anchored on its last line of text
+                None => {
+                    let line = (0..self.lines())
+                        .rev()
+                        .find(|&line| !self.line_is_blank(line))
+                        .unwrap_or(0);
+                    self.reflow(max_row, max_col, &mut Cursor::new(Line(line), Column(0)));
+                }
+            }
+            std::mem::swap(&mut self.data, &mut self.inactive_data);
+            std::mem::swap(&mut self.wrapped, &mut self.inactive_wrapped);
+
+            // Full screen applications redraw themselves
+            self.data.resize(max_col, max_row, |_| true);
+            self.wrapped.resize(max_row, false);
+        } else {
+            self.reflow(max_row, max_col, cursor);
+            self.inactive_data.resize(max_col, max_row, |_| true);
+            self.inactive_wrapped.resize(max_row, false);
+        }
+
+        cursor.line = Line(cursor.line.0.min(max_row - 1));
+        cursor.column = Column(cursor.column.0.min(max_col - 1));
+        self.tab_stops = default_tab_stops(max_col);
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = max_row - 1;
+        self.display_offset = 0;
+        self.damage_all();
+    }
+
+    /// Rewrap the history and the grid to `max_col` columns, joining soft wrapped lines
+    fn reflow(&mut self, max_row: usize, max_col: usize, cursor: &mut Cursor) {
+        let columns = self.columns();
+        let cursor_row = self.history.len() + cursor.line.0;
+
+        // Every line of the history and the grid, oldest first
+        let mut rows: Vec<HistoryLine> = self.history.drain(..).collect();
+        for line in 0..self.lines() {
+            let cells = (0..columns)
+                .map(|col| self.data[Line(line)][Column(col)].clone())
+                .collect();
+            rows.push(HistoryLine {
+                cells,
+                wrapped: self.wrapped[line],
+            });
+        }
+        // Blank lines below the cursor would push the text into the history
+        while rows.len() > cursor_row + 1
+            && rows
+                .last()
+                .is_some_and(|row| !row.wrapped && row.cells.iter().all(is_blank))
+        {
+            rows.pop();
+        }
+        if let Some(last) = rows.last_mut() {
+            last.wrapped = false;
         }
+
+        let mut reflowed: Vec<HistoryLine> = Vec::with_capacity(rows.len());
+        let mut text: Vec<Cell> = Vec::new();
+        let mut cursor_offset = None;
+        let mut new_cursor = (0, 0);
+        for (index, row) in rows.into_iter().enumerate() {
+            if index == cursor_row {
+                cursor_offset = Some(text.len() + cursor.column.0);
+            }
+            text.extend(row.cells);
+            if row.wrapped {
+                continue;
+            }
+
+            // The line ends here, split its text at the new width
+            let len = text
+                .iter()
+                .rposition(|cell| !is_blank(cell))
+                .map_or(0, |i| i + 1);
+            text.truncate(len);
+            let mut count = len.div_ceil(max_col).max(1);
+            if let Some(offset) = cursor_offset.take() {
+                count = count.max(offset / max_col + 1);
+                new_cursor = (reflowed.len() + offset / max_col, offset % max_col);
+            }
+            let mut cells = std::mem::take(&mut text).into_iter();
+            for i in 0..count {
+                reflowed.push(HistoryLine {
+                    cells: cells.by_ref().take(max_col).collect(),
+                    wrapped: i + 1 < count,
+                });
+            }
+        }
+
+        // The grid shows the last lines, unless the cursor would end up above it
+        let start = reflowed.len().saturating_sub(max_row).min(new_cursor.0);
+        let grid_rows = reflowed.split_off(start);
+        self.history = reflowed.into();
+        let overflow = self.history.len().saturating_sub(self.scrollback_limit);
+        self.history.drain(..overflow);
+
+        self.data = Grid::new(max_col, max_row);
+        self.wrapped = vec![false; max_row];
+        for (line, row) in grid_rows.into_iter().take(max_row).enumerate() {
+            self.wrapped[line] = row.wrapped;
+            for (col, cell) in row.cells.into_iter().enumerate() {
+                self.data[Line(line)][Column(col)] = cell;
+            }
+        }
+        cursor.line = Line(new_cursor.0 - start);
+        cursor.column = Column(new_cursor.1);
+    }
+
+    /// Whether a line of the grid has no text and doesn't continue on the next one
+    fn line_is_blank(&self, line: usize) -> bool {
+        !self.wrapped[line]
+            && (0..self.columns()).all(|col| is_blank(&self.data[Line(line)][Column(col)]))
+    }
+
+    pub fn lines(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.data[Line(0)].len()
+    }
+
+    /// Lines between the top and bottom scroll margins
+    pub fn scroll_region(&self) -> Range<usize> {
+        self.scroll_top..self.scroll_bottom + 1
+    }
+
+    /// Set the scroll margins from DECSTBM, returns false when the margins are invalid.
+    ///
+    /// * `top`: Zero based top margin
+    /// * `bottom`: One based bottom margin, zero means the last line
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) -> bool {
+        let bottom = match bottom {
+            0 => self.lines(),
+            bottom => bottom.min(self.lines()),
+        };
+        // The region has to be at least two lines
+        if top + 1 >= bottom {
+            return false;
+        }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom - 1;
+        true
     }
 
-    pub fn resize(&mut self, max_row: usize, max_col: usize) {
-        self.data.resize(max_col, max_row, |_| true);
+    /// Scroll the scroll region up, blank lines come in at the bottom.
+    /// Lines leaving the top of the screen go to the history.
+    pub fn scroll_up(&mut self, count: usize) {
+        let region = self.scroll_region();
+        let count = count.min(region.len());
+        // Full screen applications on the alternate screen don't fill the history
+        if region == (0..self.lines()) && !self.alt_screen {
+            self.push_history(count);
+        }
+        self.scroll_lines_up(region, count);
+    }
+
+    /// Scroll the scroll region down, blank lines come in at the top
+    pub fn scroll_down(&mut self, count: usize) {
+        self.scroll_lines_down(self.scroll_region(), count);
+    }
+
+    /// Move the lines in `range` up, blank lines in the current background come in at the bottom
+    fn scroll_lines_up(&mut self, range: Range<usize>, count: usize) {
+        let count = count.min(range.len());
+        for line in range.start..range.end - count {
+            self.copy_line(Line(line + count), Line(line));
+        }
+        self.blank_lines(range.end - count..range.end);
+        range.for_each(|line| self.damage_line(Line(line)));
+    }
+
+    /// Move the lines in `range` down, blank lines in the current background come in at the top
+    fn scroll_lines_down(&mut self, range: Range<usize>, count: usize) {
+        let count = count.min(range.len());
+        for line in (range.start + count..range.end).rev() {
+            self.copy_line(Line(line - count), Line(line));
+        }
+        self.blank_lines(range.start..range.start + count);
+        range.for_each(|line| self.damage_line(Line(line)));
+    }
+
+    /// IL, insert blank lines at the cursor line, pushing the lines below it out of the region
+    pub fn insert_lines(&mut self, cursor: &Cursor, count: usize) {
+        let region = self.scroll_region();
+        if region.contains(&cursor.line.0) {
+            self.scroll_lines_down(cursor.line.0..region.end, count);
+        }
+    }
+
+    /// DL, delete lines from the cursor line, blank lines come in at the bottom of the region
+    pub fn delete_lines(&mut self, cursor: &Cursor, count: usize) {
+        let region = self.scroll_region();
+        if region.contains(&cursor.line.0) {
+            self.scroll_lines_up(cursor.line.0..region.end, count);
+        }
+    }
+
+    /// ICH, insert blank cells at the cursor, pushing the rest of the line off the right edge
+    pub fn insert_chars(&mut self, cursor: &Cursor, count: usize) {
+        self.insert_cells(cursor.line, cursor.column.0, count);
+    }
+
+    /// DCH, delete cells at the cursor, blank cells come in at the right edge
+    pub fn delete_chars(&mut self, cursor: &Cursor, count: usize) {
+        self.delete_cells(cursor.line, cursor.column.0, count);
+    }
+
+    /// ECH, blank cells from the cursor on without moving the rest of the line
+    pub fn erase_chars(&mut self, cursor: &Cursor, count: usize) {
+        let start = cursor.column.0.min(self.columns());
+        let end = (start + count).min(self.columns());
+        for col in start..end {
+            self.data[cursor.line][Column(col)] = self.blank_cell();
+        }
+        self.damage_line(cursor.line);
+    }
+
+    /// DECIC, insert blank columns at the cursor on every line of the scroll region
+    pub fn insert_columns(&mut self, cursor: &Cursor, count: usize) {
+        let region = self.scroll_region();
+        if region.contains(&cursor.line.0) {
+            region.for_each(|line| self.insert_cells(Line(line), cursor.column.0, count));
+        }
+    }
+
+    /// DECDC, delete columns at the cursor on every line of the scroll region
+    pub fn delete_columns(&mut self, cursor: &Cursor, count: usize) {
+        let region = self.scroll_region();
+        if region.contains(&cursor.line.0) {
+            region.for_each(|line| self.delete_cells(Line(line), cursor.column.0, count));
+        }
+    }
+
+    /// Move the columns of the scroll region right, blank columns come in on the left
+    pub fn scroll_columns_right(&mut self, count: usize) {
+        let region = self.scroll_region();
+        region.for_each(|line| self.insert_cells(Line(line), 0, count));
+    }
+
+    /// Move the columns of the scroll region left, blank columns come in on the right
+    pub fn scroll_columns_left(&mut self, count: usize) {
+        let region = self.scroll_region();
+        region.for_each(|line| self.delete_cells(Line(line), 0, count));
+    }
+
+    fn insert_cells(&mut self, line: Line, col: usize, count: usize) {
+        let columns = self.columns();
+        let col = col.min(columns);
+        let count = count.min(columns - col);
+        for i in (col + count..columns).rev() {
+            let cell = self.data[line][Column(i - count)].clone();
+            self.data[line][Column(i)] = cell;
+        }
+        for i in col..col + count {
+            self.data[line][Column(i)] = self.blank_cell();
+        }
+        self.damage_line(line);
+    }
+
+    fn delete_cells(&mut self, line: Line, col: usize, count: usize) {
+        let columns = self.columns();
+        let col = col.min(columns);
+        let count = count.min(columns - col);
+        for i in col..columns - count {
+            let cell = self.data[line][Column(i + count)].clone();
+            self.data[line][Column(i)] = cell;
+        }
+        for i in columns - count..columns {
+            self.data[line][Column(i)] = self.blank_cell();
+        }
+        self.damage_line(line);
+    }
+
+    /// An empty cell in the current background color, left behind by editing operations (BCE)
+    fn blank_cell(&self) -> Cell {
+        Cell {
+            c: ' ',
+            fg: None,
+            bg: self.bg,
+            attr: Attribute::default(),
+            erasable: true,
+            dirty: true,
+        }
+    }
+
+    /// Replace whole lines with blank cells
+    fn blank_lines(&mut self, range: Range<usize>) {
+        for line in range {
+            for col in 0..self.columns() {
+                self.data[Line(line)][Column(col)] = self.blank_cell();
+            }
+            self.wrapped[line] = false;
+        }
+    }
+
+    pub fn alt_screen(&self) -> bool {
+        self.alt_screen
+    }
+
+    /// Switch between the primary and the alternate screen, both keep their content.
+    /// The view jumps back to the grid since the alternate screen has no history.
+    pub fn set_alt_screen(&mut self, enable: bool) {
+        if self.alt_screen == enable {
+            return;
+        }
+        std::mem::swap(&mut self.data, &mut self.inactive_data);
+        std::mem::swap(&mut self.wrapped, &mut self.inactive_wrapped);
+        self.alt_screen = enable;
+        self.display_offset = 0;
+        self.damage_all();
+    }
+
+    /// Erase every line of the screen that is shown
+    pub fn clear_screen(&mut self) {
+        self.erase_range_unchecked(0..self.lines(), |_| true);
+    }
+
+    /// Copy the top `count` lines of the grid into the history
+    fn push_history(&mut self, count: usize) {
+        for line in 0..count {
+            let cells = (0..self.columns())
+                .map(|col| self.data[Line(line)][Column(col)].clone())
+                .collect();
+            self.history.push_back(HistoryLine {
+                cells,
+                wrapped: self.wrapped[line],
+            });
+        }
+        let overflow = self.history.len().saturating_sub(self.scrollback_limit);
+        self.history.drain(..overflow);
+
+        // A scrolled back view stays on the lines it shows
+        if self.display_offset > 0 {
+            self.display_offset = (self.display_offset + count).min(self.history.len());
+            self.damage_all();
+        }
+    }
+
+    /// Set how many lines the history keeps, dropping the oldest ones
+    pub fn set_scrollback_limit(&mut self, limit: usize) {
+        self.scrollback_limit = limit;
+        let overflow = self.history.len().saturating_sub(limit);
+        self.history.drain(..overflow);
+        self.display_offset = self.display_offset.min(self.history.len());
+        self.damage_all();
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// First line on screen, counting the history lines before the grid
+    pub fn view_start(&self) -> Line {
+        Line(self.history.len() - self.display_offset)
+    }
+
+    /// Scroll the view by `delta` lines, positive goes back into the history.
+    /// Returns whether the view moved.
+    pub fn scroll_display(&mut self, delta: isize) -> bool {
+        let offset = self
+            .display_offset
+            .saturating_add_signed(delta)
+            .min(self.history.len());
+        if offset == self.display_offset {
+            return false;
+        }
+        self.display_offset = offset;
+        self.damage_all();
+        true
+    }
+
+    /// Scroll the view back to the bottom of the grid, returns whether it moved
+    pub fn reset_display(&mut self) -> bool {
+        self.scroll_display(-(self.display_offset as isize))
+    }
+
+    /// Cells of the given lines on screen, positioned from the start of the history
+    /// like [`Terminal::view_start`]
+    pub fn view_iter<'a>(
+        &'a self,
+        lines: &'a BTreeSet<usize>,
+    ) -> impl Iterator<Item = ViewCell<'a>> + 'a {
+        let start = self.view_start().0;
+        lines
+            .iter()
+            .filter(|line| **line < self.lines())
+            .flat_map(move |line| {
+                let line = start + line;
+                let columns = match line.checked_sub(self.history.len()) {
+                    Some(row) => self.data[Line(row)].len(),
+                    None => self.history[line].cells.len(),
+                };
+                (0..columns).map(move |col| ViewCell {
+                    line: Line(line),
+                    column: Column(col),
+                    cell: match line.checked_sub(self.history.len()) {
+                        Some(row) => &self.data[Line(row)][Column(col)],
+                        None => &self.history[line].cells[col],
+                    },
+                })
+            })
+    }
+
+    fn copy_line(&mut self, from: Line, to: Line) {
+        for col in 0..self.columns() {
+            let cell = self.data[from][Column(col)].clone();
+            self.data[to][Column(col)] = cell;
+        }
+        self.wrapped[to.0] = self.wrapped[from.0];
+    }
+
+    /// Whether a line was soft wrapped, so its text continues on the next line.
+    /// Lines are counted from the start of the history like [`Terminal::view_start`].
+    pub fn is_wrapped(&self, line: Line) -> bool {
+        match line.0.checked_sub(self.history.len()) {
+            Some(row) => self.wrapped.get(row).copied().unwrap_or(false),
+            None => self.history[line.0].wrapped,
+        }
+    }
+
+    /// Column of the next tab stop after `col`, the last column when there is none
+    pub fn next_tab_stop(&self, col: usize) -> usize {
+        let last = self.columns() - 1;
+        (col + 1..last)
+            .find(|col| self.tab_stops[*col])
+            .unwrap_or(last)
+    }
+
+    /// Column of the previous tab stop before `col`, the first column when there is none
+    pub fn previous_tab_stop(&self, col: usize) -> usize {
+        (0..col.min(self.columns()))
+            .rev()
+            .find(|col| self.tab_stops[*col])
+            .unwrap_or(0)
+    }
+
+    /// HTS, set a tab stop at `col`
+    pub fn set_tab_stop(&mut self, col: usize) {
+        if let Some(stop) = self.tab_stops.get_mut(col) {
+            *stop = true;
+        }
+    }
+
+    /// TBC, clear the tab stop at `col` or every tab stop when `col` is `None`
+    pub fn clear_tab_stop(&mut self, col: Option<usize>) {
+        match col {
+            Some(col) => {
+                if let Some(stop) = self.tab_stops.get_mut(col) {
+                    *stop = false;
+                }
+            }
+            None => self.tab_stops.fill(false),
+        }
+    }
+
+    /// Drop a pending wrap, the cursor moved away from the right margin
+    pub fn clear_wrap_pending(&mut self) {
+        self.wrap_pending = false;
+    }
+
+    /// Move the cursor down a line, scrolling the region up when the cursor is on its bottom margin
+    pub fn index(&mut self, cursor: &mut Cursor) {
+        let line = cursor.line.0;
+        if line + 1 == self.scroll_region().end {
+            self.scroll_up(1);
+        } else if line + 1 < self.lines() {
+            cursor.line.0 += 1;
+        }
+    }
+
+    /// Write a cell at the cursor and move the cursor right. Writing the last column leaves
+    /// the cursor there with a pending wrap, the next cell wraps to the next line under DECAWM
+    /// and overwrites the last column otherwise. In IRM the rest of the line moves right.
+    fn put_cell(&mut self, cursor: &mut Cursor, cell: Cell) {
+        if std::mem::take(&mut self.wrap_pending) && self.modes.private(PrivateMode::Autowrap) {
+            self.wrapped[cursor.line.0] = true;
+            cursor.column = Column(0);
+            self.index(cursor);
+        }
+
+        let last = self.columns() - 1;
+        cursor.line = Line(cursor.line.0.min(self.lines() - 1));
+        cursor.column = Column(cursor.column.0.min(last));
+        if self.modes.ansi(AnsiMode::Insert) {
+            self.insert_cells(cursor.line, cursor.column.0, 1);
+        }
+        self.data[cursor.line][cursor.column] = cell;
+        if cursor.column.0 < last {
+            cursor.column.0 += 1;
+        } else {
+            self.wrap_pending = true;
+        }
+    }
+
+    pub fn damage_line(&mut self, line: Line) {
+        self.damaged_lines.insert(line.0);
+    }
+
+    pub fn damage_all(&mut self) {
+        self.full_damage = true;
+    }
+
+    /// Lines on screen that have to be drawn again, either damaged explicitly or holding a dirty cell
+    pub fn damaged_lines(&self) -> BTreeSet<usize> {
+        if self.full_damage {
+            return (0..self.data.len()).collect();
+        }
+
+        let mut lines = self.damaged_lines.clone();
+        for i in 0..self.data.len() {
+            let row = &self.data[Line(i)];
+            if (0..row.len()).any(|col| row[Column(col)].dirty) {
+                lines.insert(i);
+            }
+        }
+        // Grid lines move down the screen while the view is scrolled back
+        lines
+            .into_iter()
+            .map(|line| line + self.display_offset)
+            .filter(|line| *line < self.data.len())
+            .collect()
+    }
+
+    /// Clear the damage after the lines on screen have been drawn
+    pub fn clear_damage(&mut self, lines: &BTreeSet<usize>) {
+        for &line in lines {
+            let Some(line) = line.checked_sub(self.display_offset) else {
+                continue;
+            };
+            if line >= self.data.len() {
+                continue;
+            }
+            (&mut self.data[Line(line)])
+                .into_iter()
+                .for_each(|cell| cell.dirty = false);
+        }
+        self.damaged_lines.clear();
+        self.full_damage = false;
     }
 
     pub fn input(&mut self, cursor: &mut Cursor, data: Vec<Cell>) {
-        self.data.input_insert(data, cursor, |_| true);
+        for cell in data {
+            self.put_cell(cursor, cell);
+        }
     }
 
+    /// Write the cells added since the last update at the cursor
     pub fn update(&mut self, cursor: &mut Cursor) {
-        self.data
-            .input_insert(std::mem::take(&mut self.write_stack), cursor, |_| true);
+        for cell in std::mem::take(&mut self.write_stack) {
+            self.put_cell(cursor, cell);
+        }
+    }
+
+    pub fn graphic_state(&self) -> GraphicState {
+        GraphicState {
+            fg: self.fg,
+            bg: self.bg,
+            attr: self.attr,
+            fg_base: self.fg_base,
+            bg_base: self.bg_base,
+        }
+    }
+
+    /// SGR parameters that bring back the current colors and attributes, for DECRQSS
+    pub fn graphic_state_sgr(&self) -> String {
+        let mut sgr = String::from("0");
+        let attributes = [
+            (Attribute::BOLD, 1),
+            (Attribute::DIM, 2),
+            (Attribute::ITALIC, 3),
+            (Attribute::UNDERLINE, 4),
+            (Attribute::BLINK, 5),
+            (Attribute::INVERSE, 7),
+            (Attribute::HIDDEN, 8),
+            (Attribute::STRIKETHROUGH, 9),
+        ];
+        for (attribute, param) in attributes {
+            if self.attr.contains(attribute) {
+                sgr.push_str(&format!(";{param}"));
+            }
+        }
+        if let Some(fg) = self.fg {
+            sgr.push_str(&format!(";{}", color_sgr(fg, 30, self.fg_base)));
+        }
+        if let Some(bg) = self.bg {
+            sgr.push_str(&format!(";{}", color_sgr(bg, 40, self.bg_base)));
+        }
+        sgr
+    }
+
+    pub fn set_graphic_state(&mut self, state: GraphicState) {
+        self.fg = state.fg;
+        self.bg = state.bg;
+        self.attr = state.attr;
+        self.fg_base = state.fg_base;
+        self.bg_base = state.bg_base;
+    }
+
+    /// RIS, clear both screens and the history and reset every mode and color,
+    /// only the size and the scrollback limit are kept
+    pub fn reset(&mut self) {
+        let scrollback_limit = self.scrollback_limit;
+        let mut palette = self.palette.clone();
+        palette.reset();
+        *self = Self::new(self.lines(), self.columns(), palette);
+        self.scrollback_limit = scrollback_limit;
     }
 
     pub fn reset_graphic(&mut self) {
-        self.fg = Color::IndexBase(7);
-        self.bg = Color::IndexBase(0);
+        self.fg = None;
+        self.bg = None;
         self.attr = Attribute::default();
+        self.fg_base = false;
+        self.bg_base = false;
     }
 
-    fn set_attr(&mut self, val: i64) {}
+    /// Turn a single SGR attribute on or off, values without an attribute are ignored
+    fn set_attr(&mut self, val: i64) {
+        match val {
+            1 => self.attr.insert(Attribute::BOLD),
+            2 => self.attr.insert(Attribute::DIM),
+            3 => self.attr.insert(Attribute::ITALIC),
+            // Double underline is drawn as a single one
+            4 | 21 => self.attr.insert(Attribute::UNDERLINE),
+            5 | 6 => self.attr.insert(Attribute::BLINK),
+            7 => self.attr.insert(Attribute::INVERSE),
+            8 => self.attr.insert(Attribute::HIDDEN),
+            9 => self.attr.insert(Attribute::STRIKETHROUGH),
+            22 => self.attr.remove(Attribute::BOLD | Attribute::DIM),
+            23 => self.attr.remove(Attribute::ITALIC),
+            24 => self.attr.remove(Attribute::UNDERLINE),
+            25 => self.attr.remove(Attribute::BLINK),
+            27 => self.attr.remove(Attribute::INVERSE),
+            28 => self.attr.remove(Attribute::HIDDEN),
+            29 => self.attr.remove(Attribute::STRIKETHROUGH),
+            _ => {}
+        }
+    }
 
-    pub fn rendition(&mut self, rendition: Vec<i64>) {
-        if rendition.len() <= 2 {
-            for val in rendition {
-                match &val {
-                    0 => self.reset_graphic(),
-                    1..=27 => self.set_attr(val),
-                    30..=37 => {
-                        if self.dark_mode {
-                            self.fg = Color::IndexBase((val - 30) as usize)
-                        } else {
-                            self.fg = Color::IndexBase((val - 30 + 8) as usize)
-                        }
-                    }
-                    38 => self.fg = Color::IndexBase(7),
-                    40..=47 => {
-                        if self.dark_mode {
-                            self.bg = Color::IndexBase((val - 30) as usize)
-                        } else {
-                            self.bg = Color::IndexBase((val - 30 + 8) as usize)
-                        }
-                    }
-                    49 => self.bg = Color::IndexBase(0),
-                    _ => {}
-                }
-            }
+    /// Apply an SGR sequence. Every parameter comes with its `:` separated sub parameters,
+    /// so `38:2::r:g:b` and `38;2;r;g;b` set the same color.
+    pub fn rendition(&mut self, rendition: Vec<Vec<i64>>) {
+        // `CSI m` is the same as `CSI 0 m`
+        if rendition.is_empty() {
+            self.reset_graphic();
             return;
         }
 
-        if rendition.len() > 2 {
-            match rendition.as_slice() {
-                [pre @ .., 38, 5, index] => {
-                    self.rendition(pre.to_vec());
-                    self.fg = Color::Index256(*index as usize);
+        let mut params = rendition.iter();
+        while let Some(param) = params.next() {
+            match param.as_slice() {
+                [] | [0] => self.reset_graphic(),
+                [38, sub @ ..] => {
+                    if let Some(color) = extended_color(sub, &mut params) {
+                        self.set_fg(Some(color), false);
+                    }
                 }
-                [pre @ .., 48, 5, index] => {
-                    self.rendition(pre.to_vec());
-                    self.fg = Color::Index256(*index as usize);
+                [48, sub @ ..] => {
+                    if let Some(color) = extended_color(sub, &mut params) {
+                        self.set_bg(Some(color), false);
+                    }
                 }
-                [38, 2, rgb @ ..] => {
-                    self.fg = Color::Rgba(RGBA {
-                        r: rgb
-                            .first()
-                            .map_or_else(|| 0, |r| (*r).try_into().unwrap_or(0)),
-                        g: rgb
-                            .get(1)
-                            .map_or_else(|| 0, |g| (*g).try_into().unwrap_or(0)),
-                        b: rgb
-                            .get(2)
-                            .map_or_else(|| 0, |b| (*b).try_into().unwrap_or(0)),
-                        a: rgb
-                            .get(3)
-                            .map_or_else(|| 255, |a| (*a).try_into().unwrap_or(255)),
-                    });
+                [39, ..] => self.set_fg(None, false),
+                [49, ..] => self.set_bg(None, false),
+                [val @ 30..=37, ..] => self.set_fg(Some(self.base_color(*val - 30)), true),
+                [val @ 40..=47, ..] => self.set_bg(Some(self.base_color(*val - 40)), true),
+                [val @ 90..=97, ..] => {
+                    self.set_fg(Some(Color::IndexBase((*val - 90 + 8) as usize)), false)
                 }
-                [48, 2, rgb @ ..] => {
-                    self.bg = Color::Rgba(RGBA {
-                        r: rgb
-                            .first()
-                            .map_or_else(|| 0, |r| (*r).try_into().unwrap_or(0)),
-                        g: rgb
-                            .get(1)
-                            .map_or_else(|| 0, |g| (*g).try_into().unwrap_or(0)),
-                        b: rgb
-                            .get(2)
-                            .map_or_else(|| 0, |b| (*b).try_into().unwrap_or(0)),
-                        a: rgb
-                            .get(3)
-                            .map_or_else(|| 255, |a| (*a).try_into().unwrap_or(255)),
-                    });
+                [val @ 100..=107, ..] => {
+                    self.set_bg(Some(Color::IndexBase((*val - 100 + 8) as usize)), false)
                 }
-                _ => {}
+                // `4:0` turns the underline off, every other style is drawn as a single line
+                [4, 0, ..] => self.set_attr(24),
+                [val, ..] => self.set_attr(*val),
             }
         }
     }
 
+    /// Set the foreground of new cells
+    ///
+    /// * `base`: The color is one of the 8 base colors, SGR 30-37
+    fn set_fg(&mut self, color: Option<Color>, base: bool) {
+        self.fg = color;
+        self.fg_base = base;
+    }
+
+    /// Set the background of new cells
+    ///
+    /// * `base`: The color is one of the 8 base colors, SGR 40-47
+    fn set_bg(&mut self, color: Option<Color>, base: bool) {
+        self.bg = color;
+        self.bg_base = base;
+    }
+
+    /// One of the 8 base colors, light mode uses the bright half of the scheme
+    fn base_color(&self, index: i64) -> Color {
+        if self.dark_mode {
+            Color::IndexBase(index as usize)
+        } else {
+            Color::IndexBase((index + 8) as usize)
+        }
+    }
+
     pub fn add_new_cell(&mut self, c: char) {
         self.write_stack.push(Cell {
             c,
             fg: self.fg,
             bg: self.bg,
-            attr: self.attr.clone(),
-            sixel_data: None,
+            attr: self.attr,
             erasable: true,
-            dirty: false,
+            dirty: true,
         });
     }
 
@@ -591,8 +2098,8 @@ impl<'config> Terminal<'config> {
             }
             data[Column(i)].c = ' ';
             data[Column(i)].dirty = true;
-            data[Column(i)].bg = Color::IndexBase(0);
-            data[Column(i)].fg = Color::IndexBase(7);
+            data[Column(i)].bg = None;
+            data[Column(i)].fg = None;
             data[Column(i)].attr = Attribute::default();
         }
     }
@@ -604,16 +2111,268 @@ impl<'config> Terminal<'config> {
         mut with_filter: impl FnMut(&&mut Cell) -> bool,
     ) {
         for i in range {
+            self.wrapped[i] = false;
             (&mut self.data[Line(i)])
                 .into_iter()
                 .take_while(&mut with_filter)
                 .for_each(|cell| {
                     cell.c = ' ';
                     cell.dirty = true;
-                    cell.bg = Color::IndexBase(0);
-                    cell.fg = Color::IndexBase(7);
+                    cell.bg = None;
+                    cell.fg = None;
                     cell.attr = Attribute::default();
                 });
         }
     }
 }
+
+/// A tab stop every 8 columns
+fn default_tab_stops(columns: usize) -> Vec<bool> {
+    (0..columns).map(|col| col > 0 && col % 8 == 0).collect()
+}
+
+/// Cells that were never written or hold an erased space with the default background
+fn is_blank(cell: &Cell) -> bool {
+    matches!(cell.c, ' ' | '\0') && cell.bg.is_none()
+}
+
+/// Color of a `38` or `48` SGR parameter. When its parts are separated by `;`
+/// they are taken from the parameters that follow.
+///
+/// * `sub`: Sub parameters of the `38` or `48`
+/// * `params`: The parameters after it
+fn extended_color<'a>(
+    sub: &[i64],
+    params: &mut impl Iterator<Item = &'a Vec<i64>>,
+) -> Option<Color> {
+    let mut next = || params.next().and_then(|param| param.first().copied());
+    match sub {
+        [5, index, ..] => index_color(*index),
+        // The color space id is optional in the `:` form
+        [2, _, r, g, b, ..] | [2, r, g, b] => rgb_color(*r, *g, *b),
+        [] => match next()? {
+            5 => index_color(next()?),
+            2 => rgb_color(next()?, next()?, next()?),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// SGR parameters of a color, `base` is 30 for the foreground and 40 for the background.
+/// Light mode stores the base colors in the bright half of the scheme, `from_base` tells
+/// them apart from the bright colors.
+fn color_sgr(color: Color, base: usize, from_base: bool) -> String {
+    match color {
+        Color::IndexBase(index) if from_base => format!("{}", base + index % 8),
+        Color::IndexBase(index) => format!("{}", base + 60 + index % 8),
+        Color::Index256(index) => format!("{};5;{index}", base + 8),
+        Color::Rgba(RGBA { r, g, b, .. }) => format!("{};2;{r};{g};{b}", base + 8),
+    }
+}
+
+fn index_color(index: i64) -> Option<Color> {
+    Some(Color::Index256(u8::try_from(index).ok()? as usize))
+}
+
+fn rgb_color(r: i64, g: i64, b: i64) -> Option<Color> {
+    Some(Color::Rgba(RGBA {
+        r: u8::try_from(r).ok()?,
+        g: u8::try_from(g).ok()?,
+        b: u8::try_from(b).ok()?,
+        a: 255,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terminal() -> Terminal {
+        sized_terminal(2, 4)
+    }
+
+    fn sized_terminal(lines: usize, columns: usize) -> Terminal {
+        let black = RGBA {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        let mut term = Terminal::new(lines, columns, Palette::new(&[black; 16]));
+        term.dark_mode = true;
+        term
+    }
+
+    #[test]
+    fn explicit_base_colors_are_not_the_defaults() {
+        let mut term = terminal();
+        term.rendition(sgr("37;40"));
+        assert_eq!(term.graphic_state_sgr(), "0;37;40");
+
+        term.rendition(sgr("39;49"));
+        assert_eq!(term.graphic_state_sgr(), "0");
+        term.rendition(sgr("1;37;40;0"));
+        assert_eq!(term.graphic_state_sgr(), "0");
+    }
+
+    /// SGR parameters as the parser groups them, `;` between parameters and `:` inside one
+    fn sgr(params: &str) -> Vec<Vec<i64>> {
+        params
+            .split(';')
+            .map(|param| {
+                param
+                    .split(':')
+                    .map(|sub| sub.parse().unwrap_or(0))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_extended_colors() {
+        let mut term = terminal();
+        term.rendition(sgr("1;38;2;10;20;30;48;5;200;4"));
+        assert_eq!(term.graphic_state_sgr(), "0;1;4;38;2;10;20;30;48;5;200");
+    }
+
+    #[test]
+    fn reads_colon_separated_colors() {
+        let expected = "0;38;2;10;20;30;48;5;200";
+        for params in [
+            "38:2::10:20:30;48:5:200",
+            "38:2:0:10:20:30;48:5:200",
+            "38:2:10:20:30;48:5:200",
+        ] {
+            let mut term = terminal();
+            term.rendition(sgr(params));
+            assert_eq!(term.graphic_state_sgr(), expected, "{params}");
+        }
+    }
+
+    #[test]
+    fn underline_styles() {
+        let mut term = terminal();
+        term.rendition(sgr("31;4:3"));
+        assert_eq!(term.graphic_state_sgr(), "0;4;31");
+        term.rendition(sgr("4:0"));
+        assert_eq!(term.graphic_state_sgr(), "0;31");
+    }
+
+    /// Write `text` at the cursor, `\n` moves to the start of the next line
+    fn write(term: &mut Terminal, cursor: &mut Cursor, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                term.update(cursor);
+                term.clear_wrap_pending();
+                cursor.column = Column(0);
+                term.index(cursor);
+            }
+            line.chars().for_each(|c| term.add_new_cell(c));
+        }
+        term.update(cursor);
+    }
+
+    fn text(cells: &[Cell]) -> String {
+        let text: String = cells.iter().map(|cell| cell.c).collect();
+        text.trim_end().to_string()
+    }
+
+    /// Text of every line of the grid
+    fn grid_text(term: &Terminal) -> Vec<String> {
+        (0..term.lines())
+            .map(|line| {
+                let cells: Vec<Cell> = (0..term.columns())
+                    .map(|col| term.data[Line(line)][Column(col)].clone())
+                    .collect();
+                text(&cells)
+            })
+            .collect()
+    }
+
+    fn history_text(term: &Terminal) -> Vec<String> {
+        term.history.iter().map(|line| text(&line.cells)).collect()
+    }
+
+    #[test]
+    fn reflow_joins_and_splits_wrapped_lines() {
+        let mut term = sized_terminal(3, 4);
+        let mut cursor = Cursor::new(Line(0), Column(0));
+        write(&mut term, &mut cursor, "abcdef");
+        assert_eq!(grid_text(&term), ["abcd", "ef", ""]);
+
+        term.resize(3, 8, &mut cursor, None);
+        assert_eq!(grid_text(&term), ["abcdef", "", ""]);
+        assert_eq!(term.wrapped, [false, false, false]);
+        assert_eq!((cursor.line.0, cursor.column.0), (0, 6));
+
+        term.resize(3, 3, &mut cursor, None);
+        assert_eq!(grid_text(&term), ["abc", "def", ""]);
+        assert_eq!(term.wrapped, [true, true, false]);
+        assert_eq!((cursor.line.0, cursor.column.0), (2, 0));
+        assert!(term.history.is_empty());
+    }
+
+    #[test]
+    fn reflow_keeps_the_cursor_on_its_cell() {
+        let mut term = sized_terminal(3, 8);
+        let mut cursor = Cursor::new(Line(0), Column(0));
+        write(&mut term, &mut cursor, "hello\nworld");
+        cursor.column = Column(2);
+
+        term.resize(3, 4, &mut cursor, None);
+        assert_eq!(history_text(&term), ["hell"]);
+        assert_eq!(grid_text(&term), ["o", "worl", "d"]);
+        assert_eq!(term.data[cursor.line][cursor.column].c, 'r');
+    }
+
+    #[test]
+    fn reflow_drops_blank_lines_below_the_cursor() {
+        let mut term = sized_terminal(3, 4);
+        let mut cursor = Cursor::new(Line(0), Column(0));
+        write(&mut term, &mut cursor, "ab");
+
+        term.resize(3, 2, &mut cursor, None);
+        assert!(term.history.is_empty());
+        assert_eq!(grid_text(&term), ["ab", "", ""]);
+        assert_eq!((cursor.line.0, cursor.column.0), (1, 0));
+    }
+
+    #[test]
+    fn reflow_respects_the_scrollback_limit() {
+        let mut term = sized_terminal(2, 4);
+        term.set_scrollback_limit(1);
+        let mut cursor = Cursor::new(Line(0), Column(0));
+        write(&mut term, &mut cursor, "abcdefghijkl");
+        assert_eq!(history_text(&term), ["abcd"]);
+
+        term.resize(2, 2, &mut cursor, None);
+        assert_eq!(history_text(&term), ["gh"]);
+        assert_eq!(grid_text(&term), ["ij", "kl"]);
+    }
+
+    #[test]
+    fn reflow_primary_screen_behind_the_alternate_screen() {
+        let mut term = sized_terminal(3, 4);
+        let mut cursor = Cursor::new(Line(0), Column(0));
+        write(&mut term, &mut cursor, "abcdef");
+        let mut saved = cursor.clone();
+        term.set_alt_screen(true);
+        write(&mut term, &mut cursor, "xyz");
+
+        term.resize(3, 8, &mut cursor, Some(&mut saved));
+        assert_eq!((saved.line.0, saved.column.0), (0, 6));
+        term.set_alt_screen(false);
+        assert_eq!(grid_text(&term), ["abcdef", "", ""]);
+    }
+
+    #[test]
+    fn reports_base_colors_in_light_mode() {
+        let mut term = terminal();
+        term.dark_mode = false;
+        term.rendition(sgr("31;101"));
+        assert_eq!(term.graphic_state_sgr(), "0;31;101");
+        term.rendition(sgr("91;41"));
+        assert_eq!(term.graphic_state_sgr(), "0;91;41");
+    }
+}
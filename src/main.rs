@@ -1,16 +1,18 @@
 use harfbuzz_rs::Face;
 use image::{ImageBuffer, Rgba, RgbaImage};
+use learn_rendering::backend::BackendKind;
 use learn_rendering::display::Display;
+use learn_rendering::pty::Pty;
 use learn_rendering::renderer::Renderer;
-use learn_rendering::App;
+use learn_rendering::{App, AppEvent, ExitAction, DEFAULT_SCROLLBACK_LINES};
 use rusttype::Scale;
 use std::io::Read;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use term::data::{Color, Column, Line, PositionedCell, ANSI_256, RGBA};
-use term::pty::PTY;
 use term::ViewPort;
 use tracing::Level;
 use vte::VTEParser;
+use winit::dpi::PhysicalSize;
 use winit::event_loop::EventLoop;
 
 fn hex_to_color(hex: &str) -> Result<RGBA, String> {
@@ -103,32 +105,78 @@ fn main() {
     let max_col = max_x / text_width;
     let max_row = max_y / line_height;
 
-    let pty = PTY::new(
-        0,
-        ViewPort {
+    let pty = Pty::spawn(
+        &ViewPort {
             x: max_row as u16,
             y: max_col as u16,
             cx: text_width as u16,
             cy: line_height as u16,
         },
+        None,
     )
     .unwrap();
 
-    let mut app = App::new(&colorscheme, scale, pty);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
 
-    let runner = EventLoop::new().unwrap();
+    // `--software` draws on the CPU instead of through wgpu
+    let backend = match args.iter().position(|arg| arg == "--software") {
+        Some(index) => {
+            args.remove(index);
+            BackendKind::Cpu
+        }
+        None => BackendKind::Gpu,
+    };
 
-    runner.run_app(&mut app).unwrap();
-}
+    // `--on-exit close|hold|respawn` picks what happens when the shell exits
+    let exit_action = match args.iter().position(|arg| arg == "--on-exit") {
+        Some(index) => {
+            let end = (index + 2).min(args.len());
+            match args.drain(index..end).nth(1).as_deref() {
+                Some("hold") => ExitAction::Hold,
+                Some("respawn") => ExitAction::Respawn,
+                _ => ExitAction::Close,
+            }
+        }
+        None => ExitAction::default(),
+    };
+
+    // `--scrollback <lines>` sets how much history is kept
+    let scrollback = match args.iter().position(|arg| arg == "--scrollback") {
+        Some(index) => {
+            let end = (index + 2).min(args.len());
+            args.drain(index..end)
+                .nth(1)
+                .and_then(|lines| lines.parse().ok())
+                .unwrap_or(DEFAULT_SCROLLBACK_LINES)
+        }
+        None => DEFAULT_SCROLLBACK_LINES,
+    };
 
-fn blend_colors(bg: Rgba<u8>, fg: Rgba<u8>, intensity: f32) -> Rgba<u8> {
-    let alpha = intensity; // Use glyph intensity as alpha
-    let inv_alpha = 1.0 - alpha;
+    // `--headless [path]` renders a single frame to a png instead of opening a window
+    let mut args = args.into_iter();
+    if args.next().as_deref() == Some("--headless") {
+        let path = args.next().unwrap_or_else(|| "output.png".to_string());
+        let mut app = App::headless(
+            &colorscheme,
+            scale,
+            pty,
+            backend,
+            PhysicalSize::new(max_x, max_y),
+        );
+        app.set_scrollback_lines(scrollback);
 
-    Rgba([
-        (fg[0] as f32 * alpha + bg[0] as f32 * inv_alpha) as u8,
-        (fg[1] as f32 * alpha + bg[1] as f32 * inv_alpha) as u8,
-        (fg[2] as f32 * alpha + bg[2] as f32 * inv_alpha) as u8,
-        255,
-    ])
+        // Give the shell some time to print its prompt
+        std::thread::sleep(Duration::from_millis(500));
+        app.update();
+        app.render_to_image().save(path).unwrap();
+        return;
+    }
+
+    let runner = EventLoop::<AppEvent>::with_user_event().build().unwrap();
+
+    let mut app = App::new(&colorscheme, scale, pty, backend, runner.create_proxy());
+    app.set_exit_action(exit_action);
+    app.set_scrollback_lines(scrollback);
+
+    runner.run_app(&mut app).unwrap();
 }
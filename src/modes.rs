@@ -0,0 +1,210 @@
+use std::collections::BTreeSet;
+
+/// DEC private modes, set with `CSI ? Pm h` and reset with `CSI ? Pm l`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PrivateMode {
+    /// DECCKM, cursor keys send `SS3` instead of `CSI` sequences
+    CursorKeys,
+    /// DECOM, cursor positions are relative to the scroll region
+    Origin,
+    /// DECAWM, text reaching the right margin continues on the next line
+    Autowrap,
+    /// X10 mouse reporting, only button presses
+    MouseX10,
+    /// att610, the cursor blinks
+    CursorBlink,
+    /// DECTCEM, the cursor is shown
+    CursorVisible,
+    /// Switch to the alternate screen
+    AltScreen,
+    /// DECNKM, the keypad sends `SS3` sequences, also set by `ESC =`
+    ApplicationKeypad,
+    /// Report button presses and releases
+    MouseNormal,
+    /// Report motion while a button is held too
+    MouseButtonEvent,
+    /// Report every motion
+    MouseAnyEvent,
+    /// Send `CSI I` and `CSI O` when the window gains or loses focus
+    FocusEvents,
+    /// Encode mouse positions as UTF-8
+    MouseUtf8,
+    /// Encode mouse reports as `CSI < ... M`
+    MouseSgr,
+    /// Encode mouse reports as `CSI ... M` with decimal parameters
+    MouseUrxvt,
+    /// Switch to the alternate screen and clear it when leaving
+    AltScreenClear,
+    /// Save the cursor like DECSC when set and restore it when reset
+    SaveCursor,
+    /// Save the cursor and switch to a cleared alternate screen
+    AltScreenSaveCursor,
+    /// Pasted text is wrapped in `CSI 200 ~` and `CSI 201 ~`
+    BracketedPaste,
+    /// The application draws a frame between setting and resetting the mode
+    SynchronizedOutput,
+}
+
+impl PrivateMode {
+    pub fn from_code(code: u16) -> Option<Self> {
+        let mode = match code {
+            1 => Self::CursorKeys,
+            6 => Self::Origin,
+            7 => Self::Autowrap,
+            9 => Self::MouseX10,
+            12 => Self::CursorBlink,
+            25 => Self::CursorVisible,
+            47 => Self::AltScreen,
+            66 => Self::ApplicationKeypad,
+            1000 => Self::MouseNormal,
+            1002 => Self::MouseButtonEvent,
+            1003 => Self::MouseAnyEvent,
+            1004 => Self::FocusEvents,
+            1005 => Self::MouseUtf8,
+            1006 => Self::MouseSgr,
+            1015 => Self::MouseUrxvt,
+            1047 => Self::AltScreenClear,
+            1048 => Self::SaveCursor,
+            1049 => Self::AltScreenSaveCursor,
+            2004 => Self::BracketedPaste,
+            2026 => Self::SynchronizedOutput,
+            _ => return None,
+        };
+        Some(mode)
+    }
+
+    /// Whether the mode changes anything yet, the others are only kept in the mode table
+    fn implemented(self) -> bool {
+        !matches!(
+            self,
+            Self::MouseX10
+                | Self::MouseNormal
+                | Self::MouseButtonEvent
+                | Self::MouseAnyEvent
+                | Self::MouseUtf8
+                | Self::MouseSgr
+                | Self::MouseUrxvt
+                | Self::BracketedPaste
+                | Self::SynchronizedOutput
+        )
+    }
+
+    /// Modes that can't be set together, setting one resets the others
+    fn exclusive(self) -> &'static [PrivateMode] {
+        match self {
+            Self::MouseX10 | Self::MouseNormal | Self::MouseButtonEvent | Self::MouseAnyEvent => &[
+                Self::MouseX10,
+                Self::MouseNormal,
+                Self::MouseButtonEvent,
+                Self::MouseAnyEvent,
+            ],
+            Self::MouseUtf8 | Self::MouseSgr | Self::MouseUrxvt => {
+                &[Self::MouseUtf8, Self::MouseSgr, Self::MouseUrxvt]
+            }
+            Self::AltScreen | Self::AltScreenClear | Self::AltScreenSaveCursor => &[
+                Self::AltScreen,
+                Self::AltScreenClear,
+                Self::AltScreenSaveCursor,
+            ],
+            _ => &[],
+        }
+    }
+}
+
+/// ANSI modes, set with `CSI Pm h` and reset with `CSI Pm l`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnsiMode {
+    /// IRM, printed text pushes the rest of the line right
+    Insert,
+    /// LNM, LF also returns the cursor and Enter sends CR LF
+    LineFeedNewLine,
+}
+
+impl AnsiMode {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            4 => Some(Self::Insert),
+            20 => Some(Self::LineFeedNewLine),
+            _ => None,
+        }
+    }
+}
+
+/// Answer to a DECRQM query, sent as `Ps` of `CSI Pm ; Ps $ y`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeReport {
+    NotRecognized = 0,
+    Set = 1,
+    Reset = 2,
+    PermanentlySet = 3,
+    PermanentlyReset = 4,
+}
+
+/// Every mode that is currently set
+#[derive(Debug, Clone)]
+pub struct Modes {
+    private: BTreeSet<PrivateMode>,
+    ansi: BTreeSet<AnsiMode>,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            private: BTreeSet::from([
+                PrivateMode::Autowrap,
+                PrivateMode::CursorBlink,
+                PrivateMode::CursorVisible,
+            ]),
+            ansi: BTreeSet::new(),
+        }
+    }
+}
+
+impl Modes {
+    pub fn private(&self, mode: PrivateMode) -> bool {
+        self.private.contains(&mode)
+    }
+
+    pub fn set_private(&mut self, mode: PrivateMode, enable: bool) {
+        if enable {
+            for other in mode.exclusive() {
+                self.private.remove(other);
+            }
+            self.private.insert(mode);
+        } else {
+            self.private.remove(&mode);
+        }
+    }
+
+    pub fn ansi(&self, mode: AnsiMode) -> bool {
+        self.ansi.contains(&mode)
+    }
+
+    pub fn set_ansi(&mut self, mode: AnsiMode, enable: bool) {
+        if enable {
+            self.ansi.insert(mode);
+        } else {
+            self.ansi.remove(&mode);
+        }
+    }
+
+    /// Answer DECRQM for a DEC private mode. Modes that are accepted but not implemented
+    /// are reported as permanently reset, so programs don't rely on them.
+    pub fn report_private(&self, code: u16) -> ModeReport {
+        match PrivateMode::from_code(code) {
+            Some(mode) if !mode.implemented() => ModeReport::PermanentlyReset,
+            Some(mode) if self.private(mode) => ModeReport::Set,
+            Some(_) => ModeReport::Reset,
+            None => ModeReport::NotRecognized,
+        }
+    }
+
+    /// Answer DECRQM for an ANSI mode
+    pub fn report_ansi(&self, code: u16) -> ModeReport {
+        match AnsiMode::from_code(code) {
+            Some(mode) if self.ansi(mode) => ModeReport::Set,
+            Some(_) => ModeReport::Reset,
+            None => ModeReport::NotRecognized,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use term::data::{ANSI_256, RGBA};
+
+/// Colors without a palette index, set with OSC 10, 11 and 12
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicColor {
+    /// Text of cells without an SGR foreground
+    Foreground,
+    /// Cells without an SGR background and the space around the grid
+    Background,
+    Cursor,
+}
+
+impl DynamicColor {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            10 => Some(Self::Foreground),
+            11 => Some(Self::Background),
+            12 => Some(Self::Cursor),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u16 {
+        match self {
+            Self::Foreground => 10,
+            Self::Background => 11,
+            Self::Cursor => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Colors {
+    indexed: [RGBA; 256],
+    foreground: RGBA,
+    background: RGBA,
+    cursor: RGBA,
+}
+
+/// The 256 indexed colors and the default colors, which programs can change
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors: Colors,
+    /// Colors from the config, restored by OSC 104, 110, 111 and 112
+    defaults: Colors,
+}
+
+impl Palette {
+    /// The first 16 colors come from the scheme, the rest are the xterm colors
+    pub fn new(scheme: &[RGBA; 16]) -> Self {
+        let defaults = Colors {
+            indexed: std::array::from_fn(|i| scheme.get(i).copied().unwrap_or(ANSI_256[i])),
+            foreground: scheme[7],
+            background: scheme[0],
+            cursor: scheme[7],
+        };
+        Self {
+            colors: defaults.clone(),
+            defaults,
+        }
+    }
+
+    pub fn color(&self, index: usize) -> RGBA {
+        self.colors.indexed[index]
+    }
+
+    pub fn set_color(&mut self, index: usize, color: RGBA) {
+        self.colors.indexed[index] = color;
+    }
+
+    pub fn reset_color(&mut self, index: usize) {
+        self.colors.indexed[index] = self.defaults.indexed[index];
+    }
+
+    pub fn reset_colors(&mut self) {
+        self.colors.indexed = self.defaults.indexed;
+    }
+
+    pub fn dynamic(&self, color: DynamicColor) -> RGBA {
+        match color {
+            DynamicColor::Foreground => self.colors.foreground,
+            DynamicColor::Background => self.colors.background,
+            DynamicColor::Cursor => self.colors.cursor,
+        }
+    }
+
+    pub fn set_dynamic(&mut self, color: DynamicColor, value: RGBA) {
+        match color {
+            DynamicColor::Foreground => self.colors.foreground = value,
+            DynamicColor::Background => self.colors.background = value,
+            DynamicColor::Cursor => self.colors.cursor = value,
+        }
+    }
+
+    pub fn reset_dynamic(&mut self, color: DynamicColor) {
+        let value = match color {
+            DynamicColor::Foreground => self.defaults.foreground,
+            DynamicColor::Background => self.defaults.background,
+            DynamicColor::Cursor => self.defaults.cursor,
+        };
+        self.set_dynamic(color, value);
+    }
+
+    /// Put every color back to the config
+    pub fn reset(&mut self) {
+        self.colors = self.defaults.clone();
+    }
+}
+
+/// Parse the color of an OSC color sequence, either `rgb:r/g/b` with 1 to 4 hex digits
+/// per channel or `#rgb` where the digits are the high bits of each channel
+pub fn parse_color(spec: &[u8]) -> Option<RGBA> {
+    let spec = std::str::from_utf8(spec).ok()?;
+    let [r, g, b] = if let Some(rgb) = spec.strip_prefix("rgb:") {
+        let mut parts = rgb.split('/');
+        let channels = [parts.next()?, parts.next()?, parts.next()?];
+        if parts.next().is_some() {
+            return None;
+        }
+        let mut scaled = [0; 3];
+        for (out, digits) in scaled.iter_mut().zip(channels) {
+            let value = hex_channel(digits)?;
+            let max = (1 << (4 * digits.len())) - 1;
+            *out = (value * 255 / max) as u8;
+        }
+        scaled
+    } else if let Some(hex) = spec.strip_prefix('#') {
+        let len = hex.len() / 3;
+        if hex.len() % 3 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        let mut high = [0; 3];
+        for (i, out) in high.iter_mut().enumerate() {
+            let value = hex_channel(&hex[i * len..(i + 1) * len])?;
+            *out = match len {
+                1 | 2 => value << (4 * (2 - len)),
+                _ => value >> (4 * (len - 2)),
+            } as u8;
+        }
+        high
+    } else {
+        return None;
+    };
+    Some(RGBA { r, g, b, a: 255 })
+}
+
+/// A color as it is reported to a `?` query, `rgb:rrrr/gggg/bbbb`
+pub fn color_spec(color: RGBA) -> String {
+    let [r, g, b] = [color.r, color.g, color.b].map(|channel| channel as u16 * 0x101);
+    format!("rgb:{r:04x}/{g:04x}/{b:04x}")
+}
+
+/// A channel of 1 to 4 hex digits
+fn hex_channel(digits: &str) -> Option<u32> {
+    if !(1..=4).contains(&digits.len()) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Option<RGBA> {
+        Some(RGBA { r, g, b, a: 255 })
+    }
+
+    #[test]
+    fn parses_rgb_specs() {
+        assert_eq!(parse_color(b"rgb:ff/80/00"), rgb(255, 128, 0));
+        // Every channel is scaled from its own number of digits
+        assert_eq!(parse_color(b"rgb:f/8/0"), rgb(255, 136, 0));
+        assert_eq!(parse_color(b"rgb:fff/000/7ff"), rgb(255, 0, 127));
+        assert_eq!(parse_color(b"rgb:ffff/8080/0000"), rgb(255, 128, 0));
+        assert_eq!(parse_color(b"rgb:F/aB/C0"), rgb(255, 171, 192));
+    }
+
+    #[test]
+    fn parses_hash_specs() {
+        assert_eq!(parse_color(b"#f80"), rgb(240, 128, 0));
+        assert_eq!(parse_color(b"#ff8000"), rgb(255, 128, 0));
+        assert_eq!(parse_color(b"#ff8800000"), rgb(255, 128, 0));
+        assert_eq!(parse_color(b"#ffff80800000"), rgb(255, 128, 0));
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in [
+            &b""[..],
+            b"red",
+            b"rgb:",
+            b"rgb:ff/ff",
+            b"rgb:ff/ff/ff/ff",
+            b"rgb:ff//ff",
+            b"rgb:gg/00/00",
+            b"rgb:12345/0/0",
+            b"#",
+            b"#ff",
+            b"#ggg",
+            b"#fffff0000000000",
+            b"#\xc3\xa9\xc3\xa9\xc3\xa9",
+            b"\xff",
+        ] {
+            assert_eq!(
+                parse_color(spec),
+                None,
+                "{:?}",
+                String::from_utf8_lossy(spec)
+            );
+        }
+    }
+
+    #[test]
+    fn query_replies_parse_back() {
+        let color = RGBA {
+            r: 255,
+            g: 128,
+            b: 1,
+            a: 255,
+        };
+        assert_eq!(color_spec(color), "rgb:ffff/8080/0101");
+        assert_eq!(parse_color(color_spec(color).as_bytes()), Some(color));
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::Error;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use term::ViewPort;
+
+/// Shell started when `$SHELL` is not set
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// A shell running on its own pseudo terminal
+pub struct Pty {
+    /// Master side of the PTY
+    io: File,
+    /// Process id of the shell
+    child: libc::pid_t,
+}
+
+impl Pty {
+    /// Open a PTY of the given size and start `$SHELL` on it as a session leader
+    ///
+    /// * `directory`: Working directory of the shell, ours when `None`
+    pub fn spawn(viewport: &ViewPort, directory: Option<&Path>) -> std::io::Result<Self> {
+        let (mut master, mut slave) = (-1, -1);
+        let size = window_size(viewport);
+        // SAFETY: every pointer is either null or outlives the call
+        if unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        } == -1
+        {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: openpty returned two open fds that nothing else owns
+        let (io, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        set_cloexec(io.as_raw_fd())?;
+        set_cloexec(slave.as_raw_fd())?;
+
+        let shell = std::env::var_os("SHELL").unwrap_or_else(|| DEFAULT_SHELL.into());
+        let mut command = Command::new(shell);
+        command
+            .stdin(slave.try_clone()?)
+            .stdout(slave.try_clone()?)
+            .stderr(slave)
+            .env("TERM", "xterm-256color");
+        if let Some(directory) = directory {
+            command.current_dir(directory);
+        }
+        // SAFETY: only async signal safe functions are called between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(Error::last_os_error());
+                }
+                // stdin is the slave side, make it the controlling terminal
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+
+        Ok(Self {
+            io,
+            child: child.id() as libc::pid_t,
+        })
+    }
+
+    pub fn io(&mut self) -> &mut File {
+        &mut self.io
+    }
+
+    /// Process id of the shell, the only process [`crate::reader::PtyReader`] reaps
+    pub fn child(&self) -> libc::pid_t {
+        self.child
+    }
+
+    /// Set the size of the PTY with `TIOCSWINSZ`, the shell gets a `SIGWINCH`
+    pub fn resize(&self, viewport: &ViewPort) -> std::io::Result<()> {
+        let size = window_size(viewport);
+        // SAFETY: `size` outlives the call and the fd stays open while `self` is borrowed
+        if unsafe { libc::ioctl(self.io.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn window_size(viewport: &ViewPort) -> libc::winsize {
+    libc::winsize {
+        ws_row: viewport.x,
+        ws_col: viewport.y,
+        ws_xpixel: viewport.y.saturating_mul(viewport.cx),
+        ws_ypixel: viewport.x.saturating_mul(viewport.cy),
+    }
+}
+
+/// Keep the fd out of the shell, it only gets the slave side as its stdio
+fn set_cloexec(fd: RawFd) -> std::io::Result<()> {
+    // SAFETY: `fd` is open for the duration of the call
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Size of a single read from the PTY
+const READ_SIZE: usize = 1 << 14;
+/// Bytes handed out by [`PtyReader::read_batch`] at once, so a flood of output
+/// can't keep the event loop from drawing
+const MAX_BATCH: usize = 1 << 16;
+
+enum Message {
+    Output(Vec<u8>),
+    /// The shell exited, with its exit code if it could be read
+    Exit(Option<i32>),
+}
+
+/// What [`PtyReader::read_batch`] collected
+#[derive(Debug, Default)]
+pub struct PtyBatch {
+    pub output: Vec<u8>,
+    /// Set once the shell exited, after all of its output was taken
+    pub exit: Option<Option<i32>>,
+}
+
+/// Wakes the event loop, at most once until the output has been taken
+struct Wakeup {
+    pending: AtomicBool,
+    wake: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            (self.wake)();
+        }
+    }
+}
+
+/// Reads the PTY on its own thread, so output is handled as soon as it arrives
+/// instead of whenever the window gets an event
+pub struct PtyReader {
+    receiver: Receiver<Message>,
+    wakeup: Arc<Wakeup>,
+}
+
+impl PtyReader {
+    /// Start reading `io` on a new thread, until the shell exits
+    ///
+    /// * `child`: Process id of the shell, reaped once the PTY closes
+    /// * `wake`: Called from the reader thread when there is output to take
+    pub fn spawn(
+        mut io: File,
+        child: libc::pid_t,
+        wake: Arc<dyn Fn() + Send + Sync>,
+    ) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let wakeup = Arc::new(Wakeup {
+            pending: AtomicBool::new(false),
+            wake,
+        });
+
+        let thread_wakeup = Arc::clone(&wakeup);
+        std::thread::Builder::new()
+            .name("pty reader".to_string())
+            .spawn(move || {
+                let mut buff = vec![0; READ_SIZE];
+                loop {
+                    match io.read(&mut buff) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if sender.send(Message::Output(buff[..n].to_vec())).is_err() {
+                                return;
+                            }
+                            thread_wakeup.notify();
+                        }
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        // The PTY may be opened non blocking
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(2));
+                        }
+                        // Reading fails with EIO once the shell closed its side
+                        Err(e) => {
+                            tracing::debug!("pty read failed: {e}");
+                            break;
+                        }
+                    }
+                }
+
+                if sender.send(Message::Exit(wait_child(child))).is_ok() {
+                    thread_wakeup.notify();
+                }
+            })?;
+
+        Ok(Self { receiver, wakeup })
+    }
+
+    /// Take the output read so far, at most about [`MAX_BATCH`] bytes.
+    /// Wakes the event loop again when more is waiting.
+    pub fn read_batch(&self) -> PtyBatch {
+        self.wakeup.pending.store(false, Ordering::Release);
+
+        let mut batch = PtyBatch::default();
+        while batch.output.len() < MAX_BATCH {
+            match self.receiver.try_recv() {
+                Ok(Message::Output(chunk)) => batch.output.extend_from_slice(&chunk),
+                Ok(Message::Exit(code)) => {
+                    batch.exit = Some(code);
+                    return batch;
+                }
+                Err(_) => return batch,
+            }
+        }
+        self.wakeup.notify();
+        batch
+    }
+}
+
+/// Reap the shell and return its exit code, a shell killed by a signal reports
+/// `128 + signal` like shells do
+fn wait_child(child: libc::pid_t) -> Option<i32> {
+    let mut status = 0;
+    loop {
+        // SAFETY: `status` outlives the call
+        if unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+            if std::io::Error::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return None;
+        }
+        if libc::WIFEXITED(status) {
+            return Some(libc::WEXITSTATUS(status));
+        }
+        if libc::WIFSIGNALED(status) {
+            return Some(128 + libc::WTERMSIG(status));
+        }
+    }
+}
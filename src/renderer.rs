@@ -1,39 +1,125 @@
-use crate::text::{GlyphVertex, TextGenerator};
+use crate::cell::{Attribute, Cell};
+use crate::display::CursorShape;
+use crate::palette::{DynamicColor, Palette};
+use crate::text::{
+    AtlasUpdate, BackgroundInstance, CellInstance, QueuedGlyph, RectInstance, TextGenerator,
+    ATLAS_SIZE,
+};
 use rusttype::Scale;
-use term::data::{Attribute, Color, Column, GridCell, Line, PositionedCell, ANSI_256, RGBA};
+use std::collections::BTreeSet;
+use term::data::{Color, Column, Line, PositionedCell, RGBA};
 
-pub struct Renderer<'config> {
+/// A run of instances that changed, starting at `offset` in the instance buffer
+#[derive(Debug, Default, Clone)]
+pub struct InstanceDamage {
+    pub offset: usize,
+    pub instances: Vec<CellInstance>,
+}
+
+/// Output of [`Renderer::prepare_render`]
+#[derive(Debug, Default)]
+pub struct RenderFrame {
+    /// Number of cells on screen, the instance buffer holds one instance per cell, row major
+    pub cells: usize,
+    /// Instances that have to be patched into the instance buffer
+    pub damage: Vec<InstanceDamage>,
+    /// Every cell background that differs from `background`, replaces the previous runs
+    pub backgrounds: Vec<BackgroundInstance>,
+    /// Rectangles blended over the glyphs, replaces the previous ones
+    pub overlays: Vec<RectInstance>,
+    /// Default background color, the screen is cleared with it
+    pub background: [u8; 4],
+    /// Width and height of a cell in pixels
+    pub cell_size: [u32; 2],
+    /// Regions of the glyph atlas that changed since the last frame
+    pub atlas_updates: Vec<AtlasUpdate>,
+}
+
+/// How the cursor is drawn, see [`Renderer::set_cursor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderCursor {
+    pub line: usize,
+    pub col: usize,
+    pub shape: CursorShape,
+    /// Only draw the outline of a block, used when the window is unfocused
+    pub hollow: bool,
+}
+
+pub struct Renderer {
     font_loader: TextGenerator,
     max_x: u32,
     max_y: u32,
     cell_width: u32,
     cell_height: u32,
+    columns: usize,
+    lines: usize,
     max_cell: usize,
     line_offset: Line,
-    colorscheme: &'config [RGBA; 16],
+    palette: Palette,
+
+    /// What is currently in the instance buffer
+    instances: Vec<CellInstance>,
+    /// Shaped glyph of every cell, kept so undamaged cells don't have to be shaped again
+    glyphs: Vec<Option<QueuedGlyph>>,
+    cursor: Option<RenderCursor>,
+    /// Cursor that is currently in the instance buffer
+    drawn_cursor: Option<RenderCursor>,
 }
 
-impl<'config> Renderer<'config> {
+impl Renderer {
+    /// Change the size of the screen in pixels. Everything drawn is thrown away,
+    /// so every line has to be passed to the next [`Renderer::prepare_render`].
     pub fn resize(&mut self, max_x: u32, max_y: u32) {
         self.max_x = max_x;
         self.max_y = max_y;
+        self.columns = (max_x / self.cell_width) as usize;
+        self.lines = (max_y / self.cell_height) as usize;
+        self.max_cell = self.columns * self.lines;
+        self.instances = blank_instances(self.columns, self.max_cell);
+        self.glyphs = vec![None; self.max_cell];
+        self.drawn_cursor = None;
     }
-    pub fn new(max_x: u32, max_y: u32, scale: Scale, colorscheme: &'config [RGBA; 16]) -> Self {
+    pub fn new(max_x: u32, max_y: u32, scale: Scale, palette: Palette) -> Self {
         let cell_height: u32 = scale.y.round() as u32;
         let cell_width: u32 = (scale.x / 2.0).round() as u32;
         let max_col = max_x / cell_width;
         let max_row = max_y / cell_height;
         Self {
-            font_loader: TextGenerator::new(cell_width, cell_height, scale),
+            font_loader: TextGenerator::new(ATLAS_SIZE, ATLAS_SIZE, scale),
             max_x,
             max_y,
             cell_width,
             cell_height,
+            columns: max_col as usize,
+            lines: max_row as usize,
             max_cell: (max_col * max_row) as usize,
             line_offset: Line(0),
-            colorscheme,
+            palette,
+            instances: blank_instances(max_col as usize, (max_col * max_row) as usize),
+            glyphs: vec![None; (max_col * max_row) as usize],
+            cursor: None,
+            drawn_cursor: None,
         }
     }
+
+    /// Set the cursor drawn by the next [`Renderer::prepare_render`], `None` hides it.
+    /// Returns whether the cursor changed since the last frame.
+    pub fn set_cursor(&mut self, cursor: Option<RenderCursor>) -> bool {
+        self.cursor = cursor;
+        self.cursor != self.drawn_cursor
+    }
+
+    /// Colors used from the next [`Renderer::prepare_render`] on, cells already drawn
+    /// keep their colors until they are passed again
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /// Set the line drawn at the top of the screen. Lines above it are skipped,
+    /// the lines that moved have to be passed to the next [`Renderer::prepare_render`].
+    pub fn set_line_offset(&mut self, line: Line) {
+        self.line_offset = line;
+    }
     // pub fn render<I, O>(&mut self, data: I)
     // where
     //     I: Iterator,
@@ -43,95 +129,327 @@ impl<'config> Renderer<'config> {
     //     self.prepare_render(data);
     // }
 
-    /// Load the cells into the buffer and prepare to render
+    /// Load the cells into the buffer and prepare to render.
+    /// Only the lines that appear in `data` are shaped again, every other line
+    /// keeps what was drawn last time.
     ///
-    /// * `data`:
-    pub fn prepare_render<'a, I, O>(&self, data: I) -> Vec<GlyphVertex>
+    /// * `data`: Cells of the damaged lines, a line has to be passed in whole
+    pub fn prepare_render<'a, I>(&mut self, data: I) -> RenderFrame
     where
         I: Iterator,
-        I::Item: PositionedCell<&'a O>,
-        O: GridCell + 'a,
+        I::Item: PositionedCell<&'a Cell>,
     {
-        let mut result = Vec::with_capacity(self.max_cell);
+        let mut damaged: BTreeSet<usize> = BTreeSet::new();
+        let mut glyphs = Vec::with_capacity(self.max_cell);
         let mut current_line: Option<Line> = None;
         let mut current_group: String = String::with_capacity(20);
         let mut start_col: Option<Column> = None;
-        let mut last_fg: Option<Color> = None;
-        let mut last_bg: Option<Color> = None;
         let mut last_attribute: Option<Attribute> = None;
 
         for cell in data {
             let (line, col) = cell.position();
+            let Some(line) = line.0.checked_sub(self.line_offset.0).map(Line) else {
+                continue;
+            };
+            let Some(index) = self.cell_index(line, col) else {
+                continue;
+            };
+            // First time we see this line, throw away what was there
+            if damaged.insert(line.0) {
+                self.clear_line(line);
+            }
             let cell = cell.cell();
-            let c = cell.char();
-            let fg = cell.fg();
-            let bg = cell.bg();
-            let attr = cell.attribute();
+            let c = cell.c;
+            let attr = cell.attr;
+
+            let (fg, bg) = self.cell_colors(cell.fg, cell.bg, attr);
+            self.instances[index].fg = fg;
+            self.instances[index].bg = bg;
 
             // current_line is only none when we're at the beginning
             // that means every things else is none too
             if current_line.is_none() {
                 current_line = Some(line);
                 start_col = Some(col);
-                last_fg = Some(*fg);
-                last_bg = Some(*bg);
-                last_attribute = Some(attr.clone());
+                last_attribute = Some(attr);
                 current_group.push(c);
                 continue;
             }
 
             // If encoutered a new line or different attributed cell
-            // drain this chunk and create new chunk
-            if current_line.is_some_and(|l| l != line)
-                || last_fg.as_ref().is_some_and(|f| f != fg)
-                || last_bg.as_ref().is_some_and(|f| f != bg)
-                || last_attribute.as_ref().is_some_and(|a| a != attr)
+            // drain this chunk and create new chunk.
+            // Colors are stored per cell so they don't split the chunk
+            if current_line.is_some_and(|l| l != line) || last_attribute.is_some_and(|a| a != attr)
             {
-                result.extend(self.font_loader.load(
-                    self.max_x,
-                    self.max_y,
+                glyphs.extend(self.font_loader.load(
                     std::mem::take(&mut current_group),
                     last_attribute.take().unwrap(),
-                    self.to_rgba(last_fg.take().unwrap()),
-                    self.to_rgba(last_bg.take().unwrap()),
                     self.cell_width,
                     self.cell_height,
-                    Line(current_line.take().unwrap().0 - self.line_offset.0),
+                    current_line.take().unwrap(),
                     start_col.take().unwrap(),
                 ));
                 start_col = Some(col);
                 current_line = Some(line);
-                last_fg = Some(*fg);
-                last_bg = Some(*bg);
-                last_attribute = Some(attr.clone())
+                last_attribute = Some(attr)
             }
 
             current_group.push(c);
         }
 
         if !current_group.is_empty() {
-            result.extend(self.font_loader.load(
-                self.max_x,
-                self.max_y,
+            glyphs.extend(self.font_loader.load(
                 std::mem::take(&mut current_group),
                 last_attribute.take().unwrap(),
-                self.to_rgba(last_fg.take().unwrap()),
-                self.to_rgba(last_bg.take().unwrap()),
                 self.cell_width,
                 self.cell_height,
-                Line(current_line.take().unwrap().0 - self.line_offset.0),
+                current_line.take().unwrap(),
                 start_col.take().unwrap(),
             ));
         }
 
-        result
+        for glyph in glyphs {
+            let (line, col) = glyph.position();
+            if let Some(index) = self.cell_index(line, col) {
+                // Combining marks share a cell with their base, only the base is drawn
+                self.glyphs[index].get_or_insert(glyph);
+            }
+        }
+
+        // Glyphs of undamaged cells are queued too so they stay in the atlas
+        let (atlas_updates, reordered) = self.font_loader.cache(self.glyphs.iter().flatten());
+        if reordered {
+            damaged.extend(0..self.lines);
+        }
+
+        for &line in &damaged {
+            for index in line * self.columns..(line + 1) * self.columns {
+                let slot = self.glyphs[index]
+                    .as_ref()
+                    .and_then(|glyph| self.font_loader.slot(glyph));
+                let instance = &mut self.instances[index];
+                match slot {
+                    Some(slot) => {
+                        instance.glyph_rect = slot.glyph_rect;
+                        instance.atlas_rect = slot.atlas_rect;
+                        instance.flags |= CellInstance::GLYPH;
+                    }
+                    None => {
+                        instance.glyph_rect = [0; 4];
+                        instance.atlas_rect = [0; 4];
+                        instance.flags &= !CellInstance::GLYPH;
+                    }
+                }
+            }
+        }
+
+        // The lines the cursor left and entered are sent again to move it
+        for cursor in [self.drawn_cursor, self.cursor].into_iter().flatten() {
+            if let Some(line) = self.screen_line(cursor.line) {
+                damaged.insert(line);
+            }
+        }
+
+        let background = pack(self.palette.dynamic(DynamicColor::Background));
+        let mut damage = self.collect_damage(&damaged);
+        let mut backgrounds = self.background_runs(background);
+        let overlays = self.draw_cursor(&mut damage, &mut backgrounds, background);
+        self.drawn_cursor = self.cursor;
+
+        RenderFrame {
+            cells: self.max_cell,
+            damage,
+            backgrounds,
+            overlays,
+            background,
+            atlas_updates,
+            cell_size: [self.cell_width, self.cell_height],
+        }
+    }
+
+    /// Reset a line to empty cells before it is drawn again
+    fn clear_line(&mut self, line: Line) {
+        let range = line.0 * self.columns..(line.0 + 1) * self.columns;
+        for index in range {
+            self.instances[index] = CellInstance {
+                cell: self.instances[index].cell,
+                ..Default::default()
+            };
+            self.glyphs[index] = None;
+        }
+    }
+
+    /// Merge consecutive damaged lines into runs of instances
+    fn collect_damage(&self, damaged: &BTreeSet<usize>) -> Vec<InstanceDamage> {
+        let mut damage: Vec<InstanceDamage> = Vec::new();
+        for &line in damaged {
+            let range = line * self.columns..(line + 1) * self.columns;
+            match damage.last_mut() {
+                Some(last) if last.offset + last.instances.len() == range.start => {
+                    last.instances.extend_from_slice(&self.instances[range]);
+                }
+                _ => damage.push(InstanceDamage {
+                    offset: range.start,
+                    instances: self.instances[range].to_vec(),
+                }),
+            }
+        }
+        damage
+    }
+
+    /// Merge neighbouring cells with the same background color.
+    /// Cells with the default background are left to the clear color.
+    fn background_runs(&self, background: [u8; 4]) -> Vec<BackgroundInstance> {
+        let mut runs = Vec::new();
+        for line in 0..self.lines {
+            let row = &self.instances[line * self.columns..(line + 1) * self.columns];
+            let mut start = 0;
+            while start < row.len() {
+                let color = row[start].bg;
+                let end = row[start..]
+                    .iter()
+                    .position(|instance| instance.bg != color)
+                    .map_or(row.len(), |len| start + len);
+
+                // Cells that were never written have a transparent background
+                if color != background && color[3] != 0 {
+                    runs.push(BackgroundInstance {
+                        cell: [start as u16, line as u16],
+                        width: (end - start) as u32,
+                        color,
+                    });
+                }
+                start = end;
+            }
+        }
+        runs
+    }
+
+    /// Draw the cursor into the frame. A filled block swaps the colors of its cell,
+    /// every other shape is returned as rectangles to draw over the glyphs.
+    ///
+    /// * `damage`: Has to contain the cursor line
+    fn draw_cursor(
+        &self,
+        damage: &mut [InstanceDamage],
+        backgrounds: &mut Vec<BackgroundInstance>,
+        background: [u8; 4],
+    ) -> Vec<RectInstance> {
+        let Some(cursor) = self.cursor else {
+            return Vec::new();
+        };
+        let Some(line) = self.screen_line(cursor.line) else {
+            return Vec::new();
+        };
+        let Some(index) = self.cell_index(Line(line), Column(cursor.col)) else {
+            return Vec::new();
+        };
+
+        let color = pack(self.palette.dynamic(DynamicColor::Cursor));
+        let x = (cursor.col as u32 * self.cell_width) as i16;
+        let y = (line as u32 * self.cell_height) as i16;
+        let (width, height) = (self.cell_width as i16, self.cell_height as i16);
+        let thickness = (width / 8).max(1);
+        let rect = |x, y, width, height| RectInstance {
+            rect: [x, y, width, height],
+            color,
+        };
+
+        match cursor.shape {
+            CursorShape::Block if cursor.hollow => vec![
+                rect(x, y, width, thickness),
+                rect(x, y + height - thickness, width, thickness),
+                rect(x, y, thickness, height),
+                rect(x + width - thickness, y, thickness, height),
+            ],
+            CursorShape::Block => {
+                let cell_bg = self.instances[index].bg;
+                let fg = if cell_bg[3] == 0 { background } else { cell_bg };
+                if let Some(run) = damage
+                    .iter_mut()
+                    .find(|run| (run.offset..run.offset + run.instances.len()).contains(&index))
+                {
+                    run.instances[index - run.offset].fg = fg;
+                }
+                backgrounds.push(BackgroundInstance {
+                    cell: [cursor.col as u16, line as u16],
+                    width: 1,
+                    color,
+                });
+                Vec::new()
+            }
+            CursorShape::Underline => vec![rect(x, y + height - thickness, width, thickness)],
+            CursorShape::Bar => vec![rect(x, y, thickness, height)],
+        }
+    }
+
+    /// Line on screen of a line in the grid
+    fn screen_line(&self, line: usize) -> Option<usize> {
+        line.checked_sub(self.line_offset.0)
+            .filter(|line| *line < self.lines)
+    }
+
+    /// Index of an on screen cell in the instance buffer
+    fn cell_index(&self, line: Line, col: Column) -> Option<usize> {
+        if line.0 < self.lines && col.0 < self.columns {
+            Some(line.0 * self.columns + col.0)
+        } else {
+            None
+        }
+    }
+
+    /// Foreground and background of a cell after applying inverse, dim and hidden
+    fn cell_colors(
+        &self,
+        fg: Option<Color>,
+        bg: Option<Color>,
+        attr: Attribute,
+    ) -> ([u8; 4], [u8; 4]) {
+        // Cells without an SGR color are drawn in the default colors
+        let mut fg = fg.map_or_else(
+            || self.palette.dynamic(DynamicColor::Foreground),
+            |fg| self.to_rgba(fg),
+        );
+        let mut bg = bg.map_or_else(
+            || self.palette.dynamic(DynamicColor::Background),
+            |bg| self.to_rgba(bg),
+        );
+        if attr.contains(Attribute::INVERSE) {
+            std::mem::swap(&mut fg, &mut bg);
+        }
+        if attr.contains(Attribute::DIM) {
+            fg = RGBA {
+                r: (fg.r as u16 * 2 / 3) as u8,
+                g: (fg.g as u16 * 2 / 3) as u8,
+                b: (fg.b as u16 * 2 / 3) as u8,
+                a: fg.a,
+            };
+        }
+        // Hidden text keeps its background, the glyph is drawn fully transparent
+        if attr.contains(Attribute::HIDDEN) {
+            fg.a = 0;
+        }
+        (pack(fg), pack(bg))
     }
 
     fn to_rgba(&self, color: Color) -> RGBA {
         match color {
             Color::Rgba(rgba) => rgba,
-            Color::IndexBase(index) => self.colorscheme[index],
-            Color::Index256(index) => ANSI_256[index],
+            Color::IndexBase(index) | Color::Index256(index) => self.palette.color(index),
         }
     }
 }
+
+fn pack(color: RGBA) -> [u8; 4] {
+    [color.r, color.g, color.b, color.a]
+}
+
+/// Instances for an empty grid, every instance only knows its cell
+fn blank_instances(columns: usize, cells: usize) -> Vec<CellInstance> {
+    (0..cells)
+        .map(|index| CellInstance {
+            cell: [(index % columns) as u16, (index / columns) as u16],
+            ..Default::default()
+        })
+        .collect()
+}
@@ -1,29 +1,183 @@
+use crate::cell::Attribute;
 use harfbuzz_rs::{shape, Feature, Font, Tag, UnicodeBuffer};
-use rusttype::gpu_cache::Cache;
-use rusttype::{point, Font as RTFont, GlyphId, Point, Rect, Scale};
-use term::data::{Attribute, Column, Line, RGBA};
+use rusttype::gpu_cache::{Cache, CachedBy};
+use rusttype::{point, Font as RTFont, GlyphId, Point, PositionedGlyph, Rect, Scale};
+use term::data::{Column, Line};
 
+/// Corner of the unit quad every cell is drawn with
 #[repr(C)]
 #[derive(Copy, Clone, Default, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct GlyphVertex {
+pub struct QuadVertex {
     pub position: [f32; 2],
-    pub tex_coords: [f32; 2],
-    pub bg: [f32; 4],
-    pub fg: [f32; 4],
 }
 
-impl GlyphVertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 4] =
-        wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32x4, 3 => Float32x4];
+impl QuadVertex {
+    /// Two triangles covering `0.0..=1.0` on both axes
+    pub const UNIT_QUAD: [QuadVertex; 6] = [
+        QuadVertex {
+            position: [0.0, 1.0],
+        },
+        QuadVertex {
+            position: [0.0, 0.0],
+        },
+        QuadVertex {
+            position: [1.0, 0.0],
+        },
+        QuadVertex {
+            position: [1.0, 0.0],
+        },
+        QuadVertex {
+            position: [1.0, 1.0],
+        },
+        QuadVertex {
+            position: [0.0, 1.0],
+        },
+    ];
+
+    const ATTRIBS: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![0 => Float32x2];
     pub const fn desc() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<GlyphVertex>() as wgpu::BufferAddress,
+            array_stride: std::mem::size_of::<QuadVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
             attributes: &Self::ATTRIBS,
         }
     }
 }
 
+/// One grid cell, drawn as an instance of [`QuadVertex::UNIT_QUAD`]
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CellInstance {
+    /// Column and line of the cell on screen
+    pub cell: [u16; 2],
+    /// Glyph rect relative to the top left of the cell: x, y, width, height in pixels
+    pub glyph_rect: [i16; 4],
+    /// Glyph rect in the atlas: x, y, width, height in pixels
+    pub atlas_rect: [u16; 4],
+    pub fg: [u8; 4],
+    pub bg: [u8; 4],
+    pub flags: u32,
+}
+
+impl CellInstance {
+    /// The cell has a glyph in the atlas
+    pub const GLYPH: u32 = 1;
+
+    const ATTRIBS: [wgpu::VertexAttribute; 6] = wgpu::vertex_attr_array![
+        1 => Uint16x2,
+        2 => Sint16x4,
+        3 => Uint16x4,
+        4 => Unorm8x4,
+        5 => Unorm8x4,
+        6 => Uint32
+    ];
+    pub const fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<CellInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+/// A run of cells on one line sharing a background color, drawn before any glyph
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BackgroundInstance {
+    /// Column and line of the first cell of the run
+    pub cell: [u16; 2],
+    /// Number of cells in the run
+    pub width: u32,
+    pub color: [u8; 4],
+}
+
+impl BackgroundInstance {
+    const ATTRIBS: [wgpu::VertexAttribute; 3] = wgpu::vertex_attr_array![
+        1 => Uint16x2,
+        2 => Uint32,
+        3 => Unorm8x4
+    ];
+    pub const fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<BackgroundInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+/// A solid rectangle in pixels blended over the glyphs, used for the cursor
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RectInstance {
+    /// x, y, width and height in pixels
+    pub rect: [i16; 4],
+    pub color: [u8; 4],
+}
+
+impl RectInstance {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![
+        1 => Sint16x4,
+        2 => Unorm8x4
+    ];
+    pub const fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<RectInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+/// Screen metrics shared by every cell
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ScreenUniform {
+    pub screen_size: [f32; 2],
+    pub cell_size: [f32; 2],
+    pub atlas_size: [f32; 2],
+    pub _padding: [f32; 2],
+}
+
+/// Where a glyph is stored in the atlas and where it is drawn inside its cell
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct AtlasSlot {
+    pub atlas_rect: [u16; 4],
+    pub glyph_rect: [i16; 4],
+}
+
+/// Width and height of the glyph atlas texture in pixels
+pub const ATLAS_SIZE: u32 = 1024;
+
+const REGULAR_FONT: usize = 0;
+const BOLD_FONT: usize = 1;
+const ITALIC_FONT: usize = 2;
+
+/// A shaped glyph positioned in its cell, see [`TextGenerator::cache`]
+#[derive(Clone, Debug)]
+pub struct QueuedGlyph {
+    font_id: usize,
+    glyph: PositionedGlyph<'static>,
+    line: Line,
+    col: Column,
+    /// Top left corner of the cell in pixels
+    origin: Point<f32>,
+}
+
+impl QueuedGlyph {
+    /// Line and column of the cell this glyph is drawn in
+    pub fn position(&self) -> (Line, Column) {
+        (self.line, self.col)
+    }
+}
+
+/// A dirty region of the glyph atlas that has to be written to the GPU texture
+#[derive(Clone, Debug)]
+pub struct AtlasUpdate {
+    pub rect: Rect<u32>,
+    pub data: Vec<u8>,
+}
+
 pub struct TextGenerator {
     bold_hb: harfbuzz_rs::Owned<Font<'static>>,
     italic_hb: harfbuzz_rs::Owned<Font<'static>>,
@@ -39,7 +193,11 @@ pub struct TextGenerator {
 impl TextGenerator {
     /// Load font
     /// TODO: change this to new implementation to load font
-    pub fn new(width: u32, height: u32, scale: Scale) -> Self {
+    ///
+    /// * `atlas_width`: Width of the glyph cache texture
+    /// * `atlas_height`: Height of the glyph cache texture
+    /// * `scale`: Font scale
+    pub fn new(atlas_width: u32, atlas_height: u32, scale: Scale) -> Self {
         let regular = include_bytes!("/home/dacbui308/.local/share/fonts/MapleMono-Regular.ttf");
         let bold = include_bytes!("/home/dacbui308/.local/share/fonts/MapleMono-Bold.ttf");
         let italic = include_bytes!("/home/dacbui308/.local/share/fonts/MapleMono-Italic.ttf");
@@ -60,78 +218,116 @@ impl TextGenerator {
             regular_rt,
             cache: Cache::builder()
                 .multithread(true)
-                .dimensions(width, height)
+                .dimensions(atlas_width, atlas_height)
                 .build(),
             scale,
         }
     }
 
-    /// Generate bitmap representation for the data
+    /// Dimensions of the glyph atlas
+    pub fn atlas_dimensions(&self) -> (u32, u32) {
+        self.cache.dimensions()
+    }
+
+    /// Shape the text into glyphs, the glyphs still have to go through
+    /// [`TextGenerator::cache`] before they can be drawn
     ///
     /// * `text`: String data
-    /// * `attribute`: Attribute
+    /// * `attribute`: Attribute, picks the bold or italic face
     /// * `cell_witdh`: Cell witdh
     /// * `text_height`: Text_height
-    #[allow(clippy::too_many_arguments)]
     pub fn load(
         &self,
-        max_x: u32,
-        max_y: u32,
         text: impl AsRef<str>,
         attribute: Attribute,
-        fg: RGBA,
-        bg: RGBA,
         cell_witdh: u32,
         cell_height: u32,
         line: Line,
         col: Column,
-    ) -> Vec<GlyphVertex> {
-        match attribute {
-            Attribute::Bold => self.load_internal(
-                max_x,
-                max_y,
-                &self.bold_hb,
-                &self.bold_rt,
-                text,
-                fg,
-                bg,
-                cell_witdh,
-                cell_height,
-                line,
-                col,
-            ),
-            _ => self.load_internal(
-                max_x,
-                max_y,
-                &self.regular_hb,
-                &self.regular_rt,
-                text,
-                fg,
-                bg,
-                cell_witdh,
-                cell_height,
-                line,
-                col,
-            ),
+    ) -> Vec<QueuedGlyph> {
+        let font_id = match (
+            attribute.contains(Attribute::BOLD),
+            attribute.contains(Attribute::ITALIC),
+        ) {
+            // There is no bold italic face, bold text falls back to the bold face
+            (true, _) => BOLD_FONT,
+            (false, true) => ITALIC_FONT,
+            (false, false) => REGULAR_FONT,
+        };
+
+        self.load_internal(font_id, text, cell_witdh, cell_height, line, col)
+    }
+
+    /// Rasterize the glyphs into the atlas.
+    /// Every glyph that is still on screen has to be passed in, glyphs left
+    /// out may be evicted from the atlas.
+    ///
+    /// Returns the atlas regions that changed and whether previously cached
+    /// glyphs were moved, in which case every [`AtlasSlot`] has to be looked up again.
+    pub fn cache<'a>(
+        &mut self,
+        glyphs: impl Iterator<Item = &'a QueuedGlyph>,
+    ) -> (Vec<AtlasUpdate>, bool) {
+        for glyph in glyphs {
+            self.cache.queue_glyph(glyph.font_id, glyph.glyph.clone());
         }
+
+        let mut updates = Vec::new();
+        let reordered = match self.cache.cache_queued(|rect, data| {
+            updates.push(AtlasUpdate {
+                rect,
+                data: data.to_vec(),
+            })
+        }) {
+            Ok(cached_by) => cached_by == CachedBy::Reordering,
+            Err(e) => {
+                tracing::warn!("failed to cache glyphs: {e}");
+                true
+            }
+        };
+
+        (updates, reordered)
+    }
+
+    /// Find where a cached glyph is in the atlas.
+    /// Whitespace and glyphs that could not be cached have no slot.
+    pub fn slot(&self, queued: &QueuedGlyph) -> Option<AtlasSlot> {
+        let (uv_rect, pixel_rect) = self.cache.rect_for(queued.font_id, &queued.glyph).ok()??;
+
+        let (atlas_width, atlas_height) = self.cache.dimensions();
+        let atlas_x = (uv_rect.min.x * atlas_width as f32).round() as u16;
+        let atlas_y = (uv_rect.min.y * atlas_height as f32).round() as u16;
+        Some(AtlasSlot {
+            atlas_rect: [
+                atlas_x,
+                atlas_y,
+                pixel_rect.width() as u16,
+                pixel_rect.height() as u16,
+            ],
+            glyph_rect: [
+                (pixel_rect.min.x - queued.origin.x as i32) as i16,
+                (pixel_rect.min.y - queued.origin.y as i32) as i16,
+                pixel_rect.width() as i16,
+                pixel_rect.height() as i16,
+            ],
+        })
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn load_internal(
         &self,
-        width: u32,
-        height: u32,
-        hb: &harfbuzz_rs::Owned<Font<'static>>,
-        rt: &RTFont<'static>,
+        font_id: usize,
         text: impl AsRef<str>,
-        fg: RGBA,
-        bg: RGBA,
         cell_witdh: u32,
         cell_height: u32,
         line: Line,
         col: Column,
-    ) -> Vec<GlyphVertex> {
+    ) -> Vec<QueuedGlyph> {
         let text = text.as_ref();
+        let (hb, rt) = match font_id {
+            BOLD_FONT => (&self.bold_hb, &self.bold_rt),
+            ITALIC_FONT => (&self.italic_hb, &self.italic_rt),
+            _ => (&self.regular_hb, &self.regular_rt),
+        };
 
         let mut res = Vec::with_capacity(text.len());
         let buf = shape(
@@ -147,8 +343,7 @@ impl TextGenerator {
 
         let position = buf.get_glyph_positions();
         let info = buf.get_glyph_infos();
-        let mut start_x = col.0 as f32 * cell_witdh as f32;
-        let mut start_y = line.0 as f32 * cell_witdh as f32;
+        let ascent = rt.v_metrics(self.scale).ascent;
 
         let mut iter = position.iter().zip(info).peekable();
 
@@ -167,121 +362,28 @@ impl TextGenerator {
                 y: self.scale.y * scale_factor,
             };
 
+            // Clusters are byte offsets into the text, every char takes one cell
+            let cell_col = Column(col.0 + text[..info.cluster as usize].chars().count());
+            let origin = point(
+                cell_col.0 as f32 * cell_witdh as f32,
+                line.0 as f32 * cell_height as f32,
+            );
+
             let x_offset = position.x_offset as f32 / 64.0;
             let y_offset = position.y_offset as f32 / 64.0;
-            let x_advance = position.x_advance as f32 / 64.0;
-            let y_advance = position.y_advance as f32 / 64.0;
-            let x = start_x + x_offset;
-            let y = y_offset + start_y;
-
-            let glyph = rt.glyph(glyph_id).scaled(scale).positioned(point(x, y));
-
-            let screen_rect = pixels_to_vertex_metrics(
-                Rect {
-                    min: rusttype::Point {
-                        x: start_x,
-                        y: start_y,
-                    },
-                    max: rusttype::Point {
-                        x: (start_x + cell_witdh as f32),
-                        y: (start_y + cell_height as f32),
-                    },
-                },
-                width as f32,
-                height as f32,
-            );
+            let x = origin.x + x_offset;
+            // rusttype positions glyphs on their baseline
+            let y = origin.y + ascent + y_offset;
 
-            let uv_rect = glyph
-                .pixel_bounding_box()
-                .map(|old| {
-                    pixels_to_vertex_metrics(
-                        Rect {
-                            min: point(old.min.x as f32, old.min.y as f32),
-                            max: point(old.max.x as f32, old.max.y as f32),
-                        },
-                        width as f32,
-                        height as f32,
-                    )
-                })
-                .unwrap_or(screen_rect);
-
-            println!("info: {info:?}");
-            println!("position: {position:?}");
-            println!("screen rect {screen_rect:?}");
-            println!("uv rect {uv_rect:?}");
-
-            let bg = [
-                bg.r as f32 / 255.0,
-                bg.g as f32 / 255.0,
-                bg.b as f32 / 255.0,
-                bg.a as f32 / 255.0,
-            ];
-            let fg = [
-                fg.r as f32 / 255.0,
-                fg.g as f32 / 255.0,
-                fg.b as f32 / 255.0,
-                fg.a as f32 / 255.0,
-            ];
-            res.extend(vec![
-                GlyphVertex {
-                    position: [screen_rect.min.x, screen_rect.max.y],
-                    tex_coords: [uv_rect.min.x, uv_rect.max.y],
-                    bg,
-                    fg,
-                },
-                GlyphVertex {
-                    position: [screen_rect.min.x, screen_rect.min.y],
-                    tex_coords: [uv_rect.min.x, uv_rect.min.y],
-                    bg,
-                    fg,
-                },
-                GlyphVertex {
-                    position: [screen_rect.max.x, screen_rect.min.y],
-                    tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                    bg,
-                    fg,
-                },
-                GlyphVertex {
-                    position: [screen_rect.max.x, screen_rect.min.y],
-                    tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                    bg,
-                    fg,
-                },
-                GlyphVertex {
-                    position: [screen_rect.max.x, screen_rect.max.y],
-                    tex_coords: [uv_rect.max.x, uv_rect.max.y],
-                    bg,
-                    fg,
-                },
-                GlyphVertex {
-                    position: [screen_rect.min.x, screen_rect.max.y],
-                    tex_coords: [uv_rect.min.x, uv_rect.max.y],
-                    bg,
-                    fg,
-                },
-            ]);
-
-            start_x += cell_witdh as f32 + x_advance;
+            res.push(QueuedGlyph {
+                font_id,
+                glyph: rt.glyph(glyph_id).scaled(scale).positioned(point(x, y)),
+                line,
+                col: cell_col,
+                origin,
+            });
         }
 
         res
     }
 }
-
-fn pixels_to_vertex_metrics(input: Rect<f32>, width: f32, height: f32) -> Rect<f32> {
-    let normalized_min_x = (input.min.x / width) * 2.0 - 1.0;
-    let normalized_min_y = 1.0 - (input.min.y / height) * 2.0; // Invert y-axis
-    let normalized_max_x = (input.max.x / width) * 2.0 - 1.0;
-    let normalized_max_y = 1.0 - (input.max.y / height) * 2.0; // Invert y-axis
-
-    Rect {
-        min: Point {
-            x: normalized_min_x,
-            y: normalized_min_y,
-        },
-        max: Point {
-            x: normalized_max_x,
-            y: normalized_max_y,
-        },
-    }
-}